@@ -1,6 +1,6 @@
 use criterion::{BenchmarkId, Criterion, criterion_group, criterion_main};
 use memmap2::{Mmap, MmapMut};
-use quackmap::Quack;
+use quackmap::{Quack, SizingPlan, SizingTarget, ValueSizes};
 use rand::rngs::StdRng;
 use rand::{Rng, SeedableRng};
 use std::fs::OpenOptions;
@@ -25,11 +25,14 @@ fn needles() -> impl Iterator<Item = u64> {
     iter::from_fn(move || Some(rng.random()))
 }
 
-fn size_needed(num_slots: usize, max_vals: usize) -> usize {
-    let header = 16;
-    let per_slot = 8;
-    let per_value = 16 + VAL_SIZE;
-    header + per_slot * num_slots + per_value * max_vals
+fn size_needed(num_slots: usize, max_vals: usize) -> u64 {
+    SizingPlan::for_workload(
+        max_vals as u64,
+        &ValueSizes::Fixed(VAL_SIZE as u64),
+        SizingTarget::Slots(num_slots as u64),
+    )
+    .unwrap()
+    .size
 }
 
 unsafe fn load_quack(path: impl AsRef<Path>) -> Quack<Mmap> {
@@ -62,8 +65,7 @@ fn construct_quack(
         .read(true)
         .open(path)
         .unwrap();
-    file.set_len(size_needed(num_slots, max_vals) as u64)
-        .unwrap();
+    file.set_len(size_needed(num_slots, max_vals)).unwrap();
 
     let mut buffer = unsafe { MmapMut::map_mut(&file).unwrap() };
     buffer.advise(memmap2::Advice::Random).unwrap();
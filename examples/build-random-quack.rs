@@ -6,7 +6,7 @@ use std::{
 use anyhow::Result;
 use clap::Parser;
 use memmap2::MmapMut;
-use quackmap::Quack;
+use quackmap::{Quack, SizingPlan, SizingTarget, ValueSizes};
 use rand::{Rng, RngCore, SeedableRng};
 use rand_xoshiro::Xoshiro256PlusPlus;
 
@@ -34,6 +34,10 @@ struct Args {
     /// stored adjacent to eachother in memory.
     #[arg(long)]
     optimize: bool,
+
+    /// Print statistics about the finished quack, such as how long chains got.
+    #[arg(long)]
+    stats: bool,
 }
 
 impl Args {
@@ -43,19 +47,12 @@ impl Args {
 }
 
 fn size_needed(entries: usize, slots: usize, value_size: usize) -> Result<usize> {
-    fn imple(entries: usize, slots: usize, value_size: usize) -> Option<usize> {
-        let header = 16;
-        let per_slot = 8;
-        let value_header = 16;
-        let per_value = value_size.checked_add(value_header)?;
-
-        let slotspace = slots.checked_mul(per_slot)?;
-        let valuespace = entries.checked_mul(per_value)?;
-
-        slotspace.checked_add(valuespace)?.checked_add(header)
-    }
-
-    imple(entries, slots, value_size).ok_or_else(|| {
+    let plan = SizingPlan::for_workload(
+        entries.try_into()?,
+        &ValueSizes::Fixed(value_size.try_into()?),
+        SizingTarget::Slots(slots.try_into()?),
+    )?;
+    plan.size.try_into().map_err(|_| {
         anyhow::anyhow!(
             "would be too large for this platform to address, are you perhaps using a 32 bit machine?"
         )
@@ -102,6 +99,19 @@ fn main() -> Result<()> {
         eprintln!("Optimized the quack in {:?}", elapsed);
     }
 
+    if args.stats {
+        let stats = quack.stats()?;
+        eprintln!(
+            "Load factor {:.2}, {} of {} slots occupied, longest chain {}",
+            stats.load_factor(),
+            stats.occupied_slots,
+            stats.num_slots,
+            stats.max_chain
+        );
+        eprintln!("Slots by chain length: {:?}", stats.chain_lengths);
+        eprintln!("Store bytes used: {} of {}", stats.used, stats.capacity);
+    }
+
     let (res, elapsed) = time(|| std::io::stdout().write_all(&quack.into_inner()));
     res?;
     if let Some(time_per_write) = per(elapsed, 1) {
@@ -131,17 +141,19 @@ fn optimize(quack: &Quack<MmapMut>) -> Result<Quack<MmapMut>> {
     let size = quack.ref_inner().len();
     let num_slots = quack.slots()?;
     let mut optimized_quack = create_mmaped_mut_quack(num_slots.try_into()?, size)?;
-    for slot in 0..num_slots {
-        for entry in quack.read(slot)? {
-            optimized_quack.write(slot, entry)?;
+    for slot in quack.iter_slots()? {
+        let (slot, entries) = slot?;
+        for entry in entries {
+            optimized_quack.write(slot, &entry)?;
         }
     }
 
     #[cfg(debug_assertions)]
     {
-        for slot in 0..num_slots {
-            let inps = Vec::<&[u8]>::from_iter(quack.read(slot)?);
-            let mut outps = Vec::<&[u8]>::from_iter(optimized_quack.read(slot)?);
+        for slot in quack.iter_slots()? {
+            let (slot, entries) = slot?;
+            let inps = Vec::from_iter(entries);
+            let mut outps = Vec::from_iter(optimized_quack.read(slot)?);
             outps.reverse();
             assert_eq!(inps, outps, "slot {} does not match", slot);
         }
@@ -0,0 +1,436 @@
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use anyhow::{Context, Result, anyhow, bail};
+use clap::{Args, ValueEnum};
+use memmap2::MmapMut;
+use quackmap::{Compression, ElementEncoding, QuackBuilder, SizingPlan, SizingTarget, ValueSizes};
+use serde_json::Value;
+
+use crate::{Hasher, parse_hex};
+
+#[derive(Args, Debug)]
+pub struct BuildArgs {
+    /// Records to build from: one JSON object per line, or CSV or TSV with a
+    /// header row naming the fields.
+    #[arg(long)]
+    input: PathBuf,
+    /// Format of the input, picked from its extension by default.
+    #[arg(long, value_enum)]
+    input_format: Option<InputFormat>,
+    /// Field holding the key, which is hashed into the quack's key.
+    #[arg(long)]
+    key_field: String,
+    /// Field holding the value.
+    #[arg(long)]
+    value_field: String,
+    /// Values are hex encoded, as `dump` prints them.
+    #[arg(long)]
+    hex_values: bool,
+    #[arg(long, value_enum, default_value_t = Hasher::Fnv1a)]
+    hasher: Hasher,
+    /// Number of slots, or "auto" to pick one meeting --load-factor.
+    #[arg(long, default_value = "auto")]
+    slots: SlotCount,
+    /// Values per slot to aim for with --slots auto.
+    #[arg(long, default_value_t = 1.0)]
+    load_factor: f64,
+    #[command(flatten)]
+    layout: LayoutArgs,
+    output: PathBuf,
+}
+
+/// How to lay out a quack, shared by the commands writing them.
+#[derive(Args, Debug)]
+pub struct LayoutArgs {
+    /// Use compact elements, saving space on short values.
+    #[arg(long)]
+    compact: bool,
+    /// Use compact elements linked by relative offsets, for quacks over 4 GiB.
+    #[arg(long, conflicts_with = "compact")]
+    relative: bool,
+    /// Only store offsets for occupied slots.
+    #[arg(long)]
+    sparse: bool,
+    /// Store repeated values once.
+    #[arg(long)]
+    dedup: bool,
+    /// Compress values with this codec.
+    #[arg(long, value_enum, default_value_t = CompressionCodec::None)]
+    compression: CompressionCodec,
+    /// Compression level for zstd.
+    #[arg(long, default_value_t = 3)]
+    zstd_level: i32,
+    /// Train a zstd dictionary of up to this many bytes on the values.
+    #[arg(long, default_value_t = 0)]
+    dictionary_size: usize,
+    /// Lay out the quack on this many threads.
+    #[arg(long, default_value_t = 1)]
+    threads: usize,
+}
+
+impl LayoutArgs {
+    pub fn builder(&self, num_slots: u64) -> QuackBuilder {
+        let elements = if self.compact {
+            ElementEncoding::Compact
+        } else if self.relative {
+            ElementEncoding::Relative
+        } else {
+            ElementEncoding::Wide
+        };
+        let compression = match self.compression {
+            CompressionCodec::None => Compression::None,
+            CompressionCodec::Lz4 => Compression::Lz4,
+            CompressionCodec::Zstd => Compression::Zstd {
+                level: self.zstd_level,
+                dictionary_size: self.dictionary_size,
+            },
+        };
+        QuackBuilder::new(num_slots)
+            .elements(elements)
+            .sparse_slots(self.sparse)
+            .dedup(self.dedup)
+            .compression(compression)
+    }
+
+    /// Whether this is the default layout, [quackmap::Format::new].
+    pub fn is_default(&self) -> bool {
+        !self.compact
+            && !self.relative
+            && !self.sparse
+            && !self.dedup
+            && matches!(self.compression, CompressionCodec::None)
+    }
+
+    /// Builds into a new file at `path`, sized exactly. Returns its size.
+    pub fn write(&self, builder: &QuackBuilder, path: &Path) -> Result<u64> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)
+            .with_context(|| format!("creating {}", path.display()))?;
+        self.write_file(builder, &file)
+    }
+
+    /// Builds into `file`, which must be empty and open for reading and
+    /// writing, sizing it exactly. Returns its size.
+    pub fn write_file(&self, builder: &QuackBuilder, mut file: &File) -> Result<u64> {
+        if self.threads > 1 {
+            let quack = builder.build_parallel(self.threads)?;
+            file.write_all(quack.ref_inner())?;
+            return Ok(quack.ref_inner().len() as u64);
+        }
+        let size = builder.size()?;
+        file.set_len(size)?;
+        // SAFETY: the file is new and ours, nothing else should be using it.
+        let mut mmap = unsafe { MmapMut::map_mut(file)? };
+        builder.build_into(&mut mmap[..])?;
+        mmap.flush()?;
+        Ok(size)
+    }
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum CompressionCodec {
+    None,
+    Lz4,
+    Zstd,
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum InputFormat {
+    Jsonl,
+    Csv,
+    Tsv,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum SlotCount {
+    Auto,
+    Fixed(u64),
+}
+
+impl FromStr for SlotCount {
+    type Err = std::num::ParseIntError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(SlotCount::Auto),
+            _ => s.parse().map(SlotCount::Fixed),
+        }
+    }
+}
+
+pub fn build(args: BuildArgs) -> Result<()> {
+    let input_format = match args.input_format {
+        Some(input_format) => input_format,
+        None => guess_format(&args.input)?,
+    };
+    let open =
+        || File::open(&args.input).with_context(|| format!("opening {}", args.input.display()));
+
+    let num_slots = match args.slots {
+        SlotCount::Fixed(slots) => slots,
+        // only the slot count is used, the builder sizes the file exactly
+        SlotCount::Auto => {
+            // count the records first rather than holding them all until we know
+            let records = read_records(&args, input_format, open()?, |_, _| Ok(()))?;
+            SizingPlan::for_workload(
+                records,
+                &ValueSizes::Fixed(0),
+                SizingTarget::LoadFactor(args.load_factor),
+            )?
+            .num_slots
+        }
+    };
+    let mut builder = args.layout.builder(num_slots);
+    read_records(&args, input_format, open()?, |k, v| {
+        Ok(builder.insert(k, v)?)
+    })?;
+
+    let size = args.layout.write(&builder, &args.output)?;
+    eprintln!(
+        "Built {} values into {num_slots} slots, {size} bytes",
+        builder.len()
+    );
+    Ok(())
+}
+
+/// Calls `add` with the key and value of every record in `input`, as they
+/// go into the quack. Returns the number of records.
+fn read_records(
+    args: &BuildArgs,
+    input_format: InputFormat,
+    input: impl Read,
+    mut add: impl FnMut(u64, &[u8]) -> Result<()>,
+) -> Result<u64> {
+    let mut records = 0;
+    let mut add = |key: &str, value: &str| -> Result<()> {
+        let k = args.hasher.hash(key)?;
+        if args.hex_values {
+            add(k, &parse_hex(value)?)?;
+        } else {
+            add(k, value.as_bytes())?;
+        }
+        records += 1;
+        Ok(())
+    };
+    match input_format {
+        InputFormat::Jsonl => {
+            for (number, line) in BufReader::new(input).lines().enumerate() {
+                let line = line?;
+                if line.trim().is_empty() {
+                    continue;
+                }
+                let record: Value = serde_json::from_str(&line)
+                    .with_context(|| format!("parsing line {}", number + 1))?;
+                let key = json_field(&record, &args.key_field, number)?;
+                let value = json_field(&record, &args.value_field, number)?;
+                add(&key, &value)?;
+            }
+        }
+        InputFormat::Csv | InputFormat::Tsv => {
+            let delimiter = match input_format {
+                InputFormat::Tsv => b'\t',
+                _ => b',',
+            };
+            let mut reader = csv::ReaderBuilder::new()
+                .delimiter(delimiter)
+                .from_reader(input);
+            let headers = reader.headers()?.clone();
+            let column = |field: &str| {
+                headers
+                    .iter()
+                    .position(|header| header == field)
+                    .ok_or_else(|| anyhow!("no {field} column"))
+            };
+            let (key_column, value_column) = (column(&args.key_field)?, column(&args.value_field)?);
+            for record in reader.records() {
+                let record = record?;
+                let field = |column| {
+                    record
+                        .get(column)
+                        .ok_or_else(|| anyhow!("record {:?} is too short", record.position()))
+                };
+                add(field(key_column)?, field(value_column)?)?;
+            }
+        }
+    }
+    Ok(records)
+}
+
+fn guess_format(path: &Path) -> Result<InputFormat> {
+    match path.extension().and_then(|extension| extension.to_str()) {
+        Some("jsonl" | "ndjson") => Ok(InputFormat::Jsonl),
+        Some("csv") => Ok(InputFormat::Csv),
+        Some("tsv") => Ok(InputFormat::Tsv),
+        _ => bail!(
+            "can't tell the format of {}, pass --input-format",
+            path.display()
+        ),
+    }
+}
+
+/// A field of a JSON record as text. Strings are taken as is, anything else
+/// as its JSON encoding.
+fn json_field(record: &Value, field: &str, line: usize) -> Result<String> {
+    match record.get(field) {
+        Some(Value::String(text)) => Ok(text.clone()),
+        Some(Value::Null) | None => bail!("line {} has no {field}", line + 1),
+        Some(value) => Ok(value.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use clap::Parser;
+
+    use super::*;
+
+    #[derive(Parser)]
+    struct Cli {
+        #[command(flatten)]
+        args: BuildArgs,
+    }
+
+    fn records(
+        input_format: InputFormat,
+        input: &str,
+        flags: &[&str],
+    ) -> Result<Vec<(u64, Vec<u8>)>> {
+        let required = [
+            "build",
+            "--input",
+            "in",
+            "--key-field",
+            "id",
+            "--value-field",
+            "value",
+        ];
+        let argv = required
+            .into_iter()
+            .chain(flags.iter().copied())
+            .chain(["out"]);
+        let args = Cli::try_parse_from(argv)?.args;
+        let mut records = Vec::new();
+        let count = read_records(&args, input_format, input.as_bytes(), |k, v| {
+            records.push((k, v.to_vec()));
+            Ok(())
+        })?;
+        assert_eq!(count, records.len() as u64);
+        Ok(records)
+    }
+
+    #[test]
+    fn fields() {
+        let expected = [(1, b"hello".to_vec()), (0x20, b"3".to_vec())];
+        let jsonl = "{\"id\": 1, \"value\": \"hello\"}\n\n{\"value\": 3, \"id\": \"0x20\"}\n";
+        assert_eq!(
+            records(InputFormat::Jsonl, jsonl, &["--hasher", "none"]).unwrap(),
+            expected
+        );
+        let csv = "value,id\nhello,1\n3,0x20\n";
+        assert_eq!(
+            records(InputFormat::Csv, csv, &["--hasher", "none"]).unwrap(),
+            expected
+        );
+        let tsv = "id\tvalue\n1\thello\n0x20\t3\n";
+        assert_eq!(
+            records(InputFormat::Tsv, tsv, &["--hasher", "none"]).unwrap(),
+            expected
+        );
+
+        assert!(records(InputFormat::Jsonl, "{\"id\": 1}\n", &[]).is_err());
+        assert!(records(InputFormat::Csv, "id,other\n1,2\n", &[]).is_err());
+    }
+
+    #[test]
+    fn hex_values() {
+        let csv = "id,value\n1,71756163\n2,\n";
+        let expected = [(1, b"quac".to_vec()), (2, Vec::new())];
+        let flags = ["--hasher", "none", "--hex-values"];
+        assert_eq!(records(InputFormat::Csv, csv, &flags).unwrap(), expected);
+        assert!(records(InputFormat::Csv, "id,value\n1,717\n", &flags).is_err());
+        assert!(records(InputFormat::Csv, "id,value\n1,zz\n", &flags).is_err());
+    }
+
+    #[test]
+    fn hashing() {
+        let csv = "id,value\n,empty\na,quack\n";
+        // FNV-1a is the default, as for get
+        let expected = [
+            (0xcbf29ce484222325, b"empty".to_vec()),
+            (0xaf63dc4c8601ec8c, b"quack".to_vec()),
+        ];
+        assert_eq!(records(InputFormat::Csv, csv, &[]).unwrap(), expected);
+        assert!(records(InputFormat::Csv, csv, &["--hasher", "none"]).is_err());
+    }
+
+    #[test]
+    fn build_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("records.csv");
+        fs::write(&input, "id,value\n1,hello\n5,world\n2,quack\n").unwrap();
+        for slots in ["4", "auto"] {
+            let output = dir.path().join(format!("{slots}.quack"));
+            let argv = [
+                "build",
+                "--input",
+                input.to_str().unwrap(),
+                "--key-field",
+                "id",
+                "--value-field",
+                "value",
+                "--hasher",
+                "none",
+                "--slots",
+                slots,
+                output.to_str().unwrap(),
+            ];
+            build(Cli::try_parse_from(argv).unwrap().args).unwrap();
+            let quack = quackmap::Quack::new(fs::read(&output).unwrap());
+            assert_eq!(quack.verify().unwrap(), 3);
+            if slots == "4" {
+                let items = quack.read(1).unwrap().collect::<Vec<_>>();
+                assert_eq!(items, [&b"world"[..], b"hello"]);
+                assert_eq!(quack.read(2).unwrap().collect::<Vec<_>>(), [&b"quack"[..]]);
+            }
+        }
+    }
+
+    #[test]
+    fn compression() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("records.csv");
+        let value = "quack".repeat(20);
+        fs::write(&input, format!("id,value\n1,{value}\n")).unwrap();
+        for codec in ["lz4", "zstd"] {
+            let output = dir.path().join(format!("{codec}.quack"));
+            let argv = [
+                "build",
+                "--input",
+                input.to_str().unwrap(),
+                "--key-field",
+                "id",
+                "--value-field",
+                "value",
+                "--hasher",
+                "none",
+                "--compression",
+                codec,
+                output.to_str().unwrap(),
+            ];
+            build(Cli::try_parse_from(argv).unwrap().args).unwrap();
+            let quack = quackmap::Quack::new(fs::read(&output).unwrap());
+            assert_ne!(quack.format().unwrap().codec(), quackmap::Codec::None);
+            let items = quack.read(1).unwrap().collect::<Vec<_>>();
+            assert_eq!(items, [value.as_bytes()]);
+        }
+    }
+}
@@ -0,0 +1,371 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result, bail};
+use clap::{Parser, Subcommand, ValueEnum};
+use memmap2::Mmap;
+use quackmap::Quack;
+
+mod build;
+mod migrate;
+
+#[derive(Parser, Debug)]
+#[command(author, version, about)]
+/// Builds and inspects quack files.
+struct Args {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Prints the header and how full the quack is.
+    Info {
+        file: PathBuf,
+        /// Only walk about this many slots for the statistics.
+        #[arg(long)]
+        sample: Option<u64>,
+    },
+    /// Prints the values stored for a key, newest first.
+    Get {
+        file: PathBuf,
+        key: String,
+        /// How the quack's keys were made, the same as given to `build`.
+        #[arg(long, value_enum, default_value_t = Hasher::Fnv1a)]
+        hasher: Hasher,
+        #[arg(long, value_enum, default_value_t = ValueFormat::Hex)]
+        format: ValueFormat,
+    },
+    /// Prints every value along with its slot, in slot order.
+    Dump {
+        file: PathBuf,
+        #[arg(long, value_enum, default_value_t = DumpFormat::Jsonl)]
+        format: DumpFormat,
+    },
+    /// Checks every list and value for damage.
+    Verify { file: PathBuf },
+    /// Builds a quack from JSONL, CSV or TSV records.
+    Build(build::BuildArgs),
+    /// Rewrites a legacy quack in the versioned format, checking it reads the same.
+    Migrate(migrate::MigrateArgs),
+    /// Prints how many slots hold how many values.
+    Hist {
+        file: PathBuf,
+        /// Only walk about this many slots.
+        #[arg(long)]
+        sample: Option<u64>,
+    },
+}
+
+/// Turns the keys of records into the hashes quacks are keyed by.
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum Hasher {
+    /// 64 bit FNV-1a of the key's bytes.
+    Fnv1a,
+    /// The key is already a hash: decimal, or hexadecimal starting with 0x.
+    None,
+}
+
+impl Hasher {
+    fn hash(self, key: &str) -> Result<u64> {
+        match self {
+            Hasher::Fnv1a => Ok(key.bytes().fold(0xcbf29ce484222325, |hash, byte| {
+                (hash ^ u64::from(byte)).wrapping_mul(0x100000001b3)
+            })),
+            Hasher::None => Ok(match key.strip_prefix("0x") {
+                Some(hex) => u64::from_str_radix(hex, 16)?,
+                None => key.parse()?,
+            }),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum ValueFormat {
+    /// One value per line, hex encoded.
+    Hex,
+    /// One value per line, invalid utf8 replaced.
+    Utf8,
+    /// The values back to back, as is.
+    Raw,
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum DumpFormat {
+    /// One {"slot": .., "value": ".."} object per line, values hex encoded.
+    Jsonl,
+    /// A slot,value header, then one row per value, values hex encoded.
+    Csv,
+}
+
+fn main() -> Result<()> {
+    let args = Args::parse();
+    let mut out = BufWriter::new(io::stdout().lock());
+    run(args.command, &mut out)?;
+    out.flush()?;
+    Ok(())
+}
+
+fn run(command: Command, out: &mut impl Write) -> Result<()> {
+    match command {
+        Command::Info { file, sample } => info(&open(&file)?, sample, out)?,
+        Command::Get {
+            file,
+            key,
+            hasher,
+            format,
+        } => {
+            let quack = open(&file)?;
+            let mut values = quack.read(hasher.hash(&key)?)?;
+            while let Some(value) = values.try_next_decoded()? {
+                match format {
+                    ValueFormat::Hex => writeln!(out, "{}", hex(&value))?,
+                    ValueFormat::Utf8 => writeln!(out, "{}", String::from_utf8_lossy(&value))?,
+                    ValueFormat::Raw => out.write_all(&value)?,
+                }
+            }
+        }
+        Command::Dump { file, format } => {
+            let quack = open(&file)?;
+            if let DumpFormat::Csv = format {
+                writeln!(out, "slot,value")?;
+            }
+            for slot in quack.iter_slots()? {
+                let (slot, mut values) = slot?;
+                while let Some(value) = values.try_next_decoded()? {
+                    match format {
+                        DumpFormat::Jsonl => {
+                            writeln!(out, r#"{{"slot":{slot},"value":"{}"}}"#, hex(&value))?
+                        }
+                        DumpFormat::Csv => writeln!(out, "{slot},{}", hex(&value))?,
+                    }
+                }
+            }
+        }
+        Command::Verify { file } => {
+            let quack = open(&file)?;
+            let entries = quack
+                .verify()
+                .map_err(|_| anyhow::anyhow!("{} is damaged", file.display()))?;
+            writeln!(out, "ok, {entries} values")?;
+        }
+        Command::Build(args) => build::build(args)?,
+        Command::Migrate(args) => migrate::migrate(args)?,
+        Command::Hist { file, sample } => {
+            let quack = open(&file)?;
+            let stats = match sample {
+                Some(slots) => quack.sampled_stats(slots)?,
+                None => quack.stats()?,
+            };
+            let widest = stats
+                .chain_lengths
+                .iter()
+                .copied()
+                .max()
+                .unwrap_or(0)
+                .max(1);
+            for (len, slots) in stats.chain_lengths.iter().enumerate() {
+                let bar = "#".repeat((slots * 40).div_ceil(widest) as usize);
+                writeln!(out, "{len:>4} {slots:>12} {bar}")?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn info(quack: &Quack<Mmap>, sample: Option<u64>, out: &mut impl Write) -> Result<()> {
+    let format = quack.format()?;
+    if format.is_versioned() {
+        writeln!(out, "format:         versioned")?;
+    } else {
+        writeln!(out, "format:         legacy")?;
+    }
+    writeln!(out, "elements:       {:?}", format.elements())?;
+    writeln!(out, "slot width:     {} bytes", format.slot_width().bytes())?;
+    writeln!(out, "sparse slots:   {}", format.sparse_slots())?;
+    writeln!(out, "shared values:  {}", format.shared_payloads())?;
+    writeln!(out, "concurrent:     {}", format.concurrent_access())?;
+    writeln!(out, "codec:          {:?}", format.codec())?;
+    writeln!(out, "slots:          {}", quack.slots()?)?;
+    writeln!(
+        out,
+        "store:          {} of {} bytes used",
+        quack.used()?,
+        quack.capacity()?
+    )?;
+
+    let stats = match sample {
+        Some(slots) => quack.sampled_stats(slots)?,
+        None => quack.stats()?,
+    };
+    if stats.is_sampled() {
+        writeln!(
+            out,
+            "sampled:        {} of {} slots",
+            stats.slots_examined, stats.num_slots
+        )?;
+    }
+    writeln!(out, "values:         {}", stats.estimated_entries())?;
+    writeln!(out, "occupied slots: {}", stats.estimated_occupied_slots())?;
+    writeln!(out, "load factor:    {:.3}", stats.load_factor())?;
+    writeln!(out, "longest chain:  {}", stats.max_chain)?;
+    writeln!(out, "mean overhead:  {:.1} bytes", stats.mean_overhead())?;
+    Ok(())
+}
+
+fn open(path: &Path) -> Result<Quack<Mmap>> {
+    let file = File::open(path).with_context(|| format!("opening {}", path.display()))?;
+    // SAFETY: the file may not be changed while mapped, the same as for any
+    // reader of a quack file.
+    let mmap = unsafe { Mmap::map(&file)? };
+    let quack = Quack::new(mmap);
+    if quack.capacity().is_err() {
+        bail!("{} is not a quack", path.display());
+    }
+    Ok(quack)
+}
+
+fn hex(value: &[u8]) -> String {
+    value.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+fn parse_hex(hex: &str) -> Result<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        bail!("odd number of hex digits in {hex:?}");
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| {
+            let byte = hex.get(i..i + 2).context("hex values must be ascii")?;
+            Ok(u8::from_str_radix(byte, 16)?)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use quackmap::QuackBuilder;
+
+    use super::*;
+
+    /// Values for keys 1 and 5 share slot 1 of 4, the one for key 2 isn't
+    /// utf8.
+    fn small_quack(dir: &Path) -> PathBuf {
+        let mut builder = QuackBuilder::new(4);
+        for (k, v) in [(1, &b"hello"[..]), (5, b"world"), (2, b"\xffquack")] {
+            builder.insert(k, v).unwrap();
+        }
+        let path = dir.join("small.quack");
+        fs::write(&path, builder.build().unwrap().ref_inner()).unwrap();
+        path
+    }
+
+    fn output(argv: &[&str]) -> Result<Vec<u8>> {
+        let args = Args::try_parse_from(["quackmap"].iter().chain(argv))?;
+        let mut out = Vec::new();
+        run(args.command, &mut out)?;
+        Ok(out)
+    }
+
+    #[test]
+    fn info() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = small_quack(dir.path());
+        let file = file.to_str().unwrap();
+        let header = "\
+format:         versioned
+elements:       Wide
+slot width:     4 bytes
+sparse slots:   false
+shared values:  false
+concurrent:     false
+codec:          None
+slots:          4
+store:          64 of 64 bytes used
+";
+        let out = output(&["info", file]).unwrap();
+        let stats = "\
+values:         3
+occupied slots: 2
+load factor:    0.750
+longest chain:  2
+mean overhead:  16.0 bytes
+";
+        assert_eq!(String::from_utf8(out).unwrap(), header.to_owned() + stats);
+
+        // every other slot, 0 and 2, scaled up to all 4
+        let out = output(&["info", file, "--sample", "2"]).unwrap();
+        let stats = "\
+sampled:        2 of 4 slots
+values:         2
+occupied slots: 2
+load factor:    0.500
+longest chain:  1
+mean overhead:  16.0 bytes
+";
+        assert_eq!(String::from_utf8(out).unwrap(), header.to_owned() + stats);
+    }
+
+    #[test]
+    fn get() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = small_quack(dir.path());
+        let file = file.to_str().unwrap();
+        let get = |key, format| output(&["get", file, key, "--hasher", "none", "--format", format]);
+
+        // newest first, and the same for every key in the slot
+        assert_eq!(get("1", "hex").unwrap(), b"776f726c64\n68656c6c6f\n");
+        assert_eq!(get("0x5", "utf8").unwrap(), b"world\nhello\n");
+        assert_eq!(get("2", "raw").unwrap(), b"\xffquack");
+        assert_eq!(get("2", "utf8").unwrap(), "\u{fffd}quack\n".as_bytes());
+        assert_eq!(get("3", "hex").unwrap(), b"");
+        assert!(get("duck", "hex").is_err());
+
+        // keys are hashed by default, "b" to slot 1
+        assert_eq!(
+            output(&["get", file, "b"]).unwrap(),
+            b"776f726c64\n68656c6c6f\n"
+        );
+    }
+
+    #[test]
+    fn dump() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = small_quack(dir.path());
+        let file = file.to_str().unwrap();
+        let jsonl = r#"{"slot":1,"value":"776f726c64"}
+{"slot":1,"value":"68656c6c6f"}
+{"slot":2,"value":"ff717561636b"}
+"#;
+        assert_eq!(
+            String::from_utf8(output(&["dump", file]).unwrap()).unwrap(),
+            jsonl
+        );
+        let csv = "slot,value\n1,776f726c64\n1,68656c6c6f\n2,ff717561636b\n";
+        let out = output(&["dump", file, "--format", "csv"]).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), csv);
+    }
+
+    #[test]
+    fn hist_and_verify() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = small_quack(dir.path());
+        let file = file.to_str().unwrap();
+        let bar = "#".repeat(20);
+        let hist = format!(
+            "   0            2 {bar}{bar}\n   1            1 {bar}\n   2            1 {bar}\n"
+        );
+        assert_eq!(
+            String::from_utf8(output(&["hist", file]).unwrap()).unwrap(),
+            hist
+        );
+        assert_eq!(output(&["verify", file]).unwrap(), b"ok, 3 values\n");
+
+        let not_quack = dir.path().join("empty");
+        fs::write(&not_quack, b"").unwrap();
+        assert!(output(&["info", not_quack.to_str().unwrap()]).is_err());
+    }
+}
@@ -0,0 +1,180 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result, bail};
+use clap::Args;
+use memmap2::Mmap;
+use quackmap::Quack;
+
+use crate::build::LayoutArgs;
+use crate::open;
+
+/// Legacy quacks migrating to the default layout are rewritten a piece at a
+/// time. Other layouts need the values laid out anew, in memory.
+#[derive(Args, Debug)]
+pub struct MigrateArgs {
+    file: PathBuf,
+    /// Where to write the migrated quack. By default it replaces the original.
+    /// Either way it is only written there once it has been checked.
+    #[arg(long)]
+    output: Option<PathBuf>,
+    /// Migrate quacks that are already versioned too, changing their options.
+    #[arg(long)]
+    force: bool,
+    #[command(flatten)]
+    layout: LayoutArgs,
+}
+
+pub fn migrate(args: MigrateArgs) -> Result<()> {
+    let quack = open(&args.file)?;
+    if !quack.is_legacy()? && !args.force {
+        bail!(
+            "{} is already versioned, pass --force to rewrite it anyway",
+            args.file.display()
+        );
+    }
+
+    // write next to the output and rename it into place once checked, so an
+    // output that is the input itself stays intact until then, and nothing
+    // already at the temporary path gets clobbered
+    let output = args.output.as_ref().unwrap_or(&args.file);
+    let temporary = output.with_extension("migrating");
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .open(&temporary)
+        .with_context(|| format!("creating {}", temporary.display()))?;
+    let written = write_checked(&args, &quack, &file, &temporary);
+    drop(file);
+    let size = match written {
+        Ok(size) => size,
+        Err(e) => {
+            fs::remove_file(&temporary)?;
+            return Err(e);
+        }
+    };
+    fs::rename(&temporary, output)?;
+    eprintln!(
+        "Migrated {} to a versioned quack of {size} bytes",
+        args.file.display()
+    );
+    Ok(())
+}
+
+/// Migrates `quack` into `file`, at `output`, and checks the result reads the
+/// same. Returns its size.
+fn write_checked(
+    args: &MigrateArgs,
+    quack: &Quack<Mmap>,
+    file: &File,
+    output: &Path,
+) -> Result<u64> {
+    let size = if quack.is_legacy()? && args.layout.is_default() {
+        let input = BufReader::new(File::open(&args.file)?);
+        let mut writer = BufWriter::new(file);
+        let size = quackmap::upgrade_legacy(input, &mut writer)?;
+        writer.flush()?;
+        size
+    } else {
+        let builder = quack.migrate_into(args.layout.builder(quack.slots()?))?;
+        args.layout.write_file(&builder, file)?
+    };
+
+    let migrated = open(output)?;
+    migrated
+        .verify()
+        .with_context(|| format!("checking {}", output.display()))?;
+    let equivalent = quack
+        .equivalent(&migrated)
+        .with_context(|| format!("comparing {}", output.display()))?;
+    if !equivalent {
+        bail!("the migrated quack doesn't match {}", args.file.display());
+    }
+    Ok(size)
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+    use quackmap::{ElementEncoding, Format, Quack};
+
+    use super::*;
+
+    #[derive(Parser)]
+    struct Cli {
+        #[command(flatten)]
+        args: MigrateArgs,
+    }
+
+    fn run(argv: &[&str]) -> Result<()> {
+        let argv = ["migrate"].iter().chain(argv);
+        migrate(Cli::try_parse_from(argv)?.args)
+    }
+
+    #[test]
+    fn migrate_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("legacy.quack");
+        let size = quackmap::calculate_store_size(4, [5, 5, 5]).unwrap();
+        let mut legacy = Quack::initialize_assume_zeroed(vec![0u8; size as usize], 4).unwrap();
+        for (k, v) in [(0, b"hello"), (5, b"world"), (0, b"again")] {
+            legacy.write(k, v).unwrap();
+        }
+        fs::write(&file, legacy.ref_inner()).unwrap();
+        let file = file.to_str().unwrap();
+
+        let output = dir.path().join("versioned.quack");
+        let output = output.to_str().unwrap();
+        for (flag, elements) in [
+            (None, ElementEncoding::Wide),
+            (Some("--compact"), ElementEncoding::Compact),
+        ] {
+            let argv = [file, "--output", output].into_iter().chain(flag);
+            run(&argv.collect::<Vec<_>>()).unwrap();
+            let migrated = Quack::new(fs::read(output).unwrap());
+            assert_eq!(migrated.format().unwrap().elements(), elements);
+            assert!(legacy.equivalent(&migrated).unwrap());
+        }
+
+        // in place, after which it is no longer legacy
+        run(&[file]).unwrap();
+        let migrated = Quack::new(fs::read(file).unwrap());
+        assert_eq!(migrated.format().unwrap(), Format::new());
+        assert!(legacy.equivalent(&migrated).unwrap());
+        assert!(run(&[file]).is_err());
+        run(&[file, "--force", "--compact"]).unwrap();
+
+        // leaves alone whatever was already where it writes before replacing
+        let stray = dir.path().join("legacy.migrating");
+        fs::write(&stray, "keep me").unwrap();
+        assert!(run(&[file, "--force"]).is_err());
+        assert_eq!(fs::read(&stray).unwrap(), b"keep me");
+    }
+
+    #[test]
+    fn output_is_input() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("legacy.quack");
+        let size = quackmap::calculate_store_size(4, [5]).unwrap();
+        let mut legacy = Quack::initialize_assume_zeroed(vec![0u8; size as usize], 4).unwrap();
+        legacy.write(1, b"hello").unwrap();
+        fs::write(&file, legacy.ref_inner()).unwrap();
+        let file = file.to_str().unwrap();
+
+        run(&[file, "--output", file]).unwrap();
+        let migrated = Quack::new(fs::read(file).unwrap());
+        assert!(!migrated.is_legacy().unwrap());
+        assert!(legacy.equivalent(&migrated).unwrap());
+
+        // a failed migration leaves the input as it was, here with slot 0
+        // pointing past the store
+        let mut damaged = legacy.into_inner();
+        damaged[16..24].copy_from_slice(&1000u64.to_be_bytes());
+        fs::write(file, &damaged).unwrap();
+        assert!(run(&[file, "--output", file]).is_err());
+        assert_eq!(fs::read(file).unwrap(), damaged);
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+}
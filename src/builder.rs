@@ -0,0 +1,804 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+
+use crate::compress::Compressor;
+use crate::{Compression, ElementEncoding, Format, OutaBounds, Quack, SlotWidth, stor, val};
+
+/// Collects entries in memory, then lays them out as an exact-sized quack
+/// with each slot's values stored contiguously, newest first.
+///
+/// Reads from a built quack return the same values in the same order as if each
+/// entry had been passed to [Quack::write] in insertion order, but walking a
+/// chain only ever moves forward through memory.
+///
+/// The slot width is picked automatically: the narrowest [SlotWidth] able to
+/// address the finished buffer.
+pub struct QuackBuilder {
+    num_slots: u64,
+    elements: ElementEncoding,
+    sparse: bool,
+    compression: Compression,
+    dedup: bool,
+    /// Payloads, back to back in insertion order.
+    values: Vec<u8>,
+    entries: Vec<Entry>,
+}
+
+struct Entry {
+    slot: u64,
+    start: usize,
+    len: usize,
+}
+
+impl QuackBuilder {
+    pub fn new(num_slots: u64) -> Self {
+        QuackBuilder {
+            num_slots,
+            elements: ElementEncoding::default(),
+            sparse: false,
+            compression: Compression::None,
+            dedup: false,
+            values: Vec::new(),
+            entries: Vec::new(),
+        }
+    }
+
+    /// Use the given element encoding for the built quack.
+    pub fn elements(mut self, elements: ElementEncoding) -> Self {
+        self.elements = elements;
+        self
+    }
+
+    /// Only store offsets for occupied slots. Lookups stay O(1) but the slots
+    /// array costs 2 bits per slot plus one offset per occupied slot, rather
+    /// than one offset per slot. Worth it when most slots are empty.
+    ///
+    /// The built quack can still be written to, but only in slots that were
+    /// occupied at build time.
+    pub fn sparse_slots(mut self, sparse: bool) -> Self {
+        self.sparse = sparse;
+        self
+    }
+
+    /// Compress values with the given codec. Values are compressed one by one
+    /// so lookups only ever decompress what they return, see
+    /// [crate::Sequence::try_next_decoded].
+    pub fn compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Store identical values once. Repeats become references to the first
+    /// copy, costing one slot width of payload. Values of 8 bytes or fewer,
+    /// the widest slot, are always stored inline, as which values are shared
+    /// is settled before the slot width is. See [QuackBuilder::stats] for what
+    /// this saved.
+    pub fn dedup(mut self, dedup: bool) -> Self {
+        self.dedup = dedup;
+        self
+    }
+
+    /// Queues an item for a given key.
+    pub fn insert(&mut self, k: u64, v: &[u8]) -> Result<(), OutaBounds> {
+        let slot = k.checked_rem(self.num_slots).ok_or(OutaBounds)?;
+        self.entries.push(Entry {
+            slot,
+            start: self.values.len(),
+            len: v.len(),
+        });
+        self.values.extend_from_slice(v);
+        Ok(())
+    }
+
+    pub fn num_slots(&self) -> u64 {
+        self.num_slots
+    }
+
+    /// Number of entries inserted so far.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// The format the quack will be built in.
+    pub fn format(&self) -> Result<Format, OutaBounds> {
+        Ok(self.plan()?.layout.format)
+    }
+
+    /// Exact size of the buffer [QuackBuilder::build_into] needs.
+    ///
+    /// With compression enabled this compresses every value, as does building,
+    /// prefer [QuackBuilder::build] which only does so once.
+    pub fn size(&self) -> Result<u64, OutaBounds> {
+        self.plan()?.size()
+    }
+
+    /// Describes how the entries would be stored, encoding every value as
+    /// [QuackBuilder::size] does.
+    pub fn stats(&self) -> Result<BuildStats, OutaBounds> {
+        let plan = self.plan()?;
+        let width = plan.layout.format.slot_width().bytes();
+        let mut stats = BuildStats {
+            entries: self.entries.len() as u64,
+            value_bytes: self.values.len() as u64,
+            ..BuildStats::default()
+        };
+        for stored in &plan.payloads {
+            match stored {
+                Stored::Inline(payload, tags) => {
+                    stats.stored_bytes += payload.len() as u64;
+                    stats.compressed_values += u64::from(tags.compressed);
+                }
+                Stored::Shared(canonical) => {
+                    stats.stored_bytes += width;
+                    stats.shared_values += 1;
+                    // a compressed original can come out shorter than the reference
+                    stats.dedup_bytes_saved +=
+                        plan.payloads[*canonical].len(width).saturating_sub(width);
+                }
+            }
+        }
+        stats.dictionary_bytes = plan.dictionary.len() as u64;
+        Ok(stats)
+    }
+
+    /// Lays out the quack in `data`, which must be at least [QuackBuilder::size]
+    /// bytes long. `data` need not be zeroed, the header and slots are cleared
+    /// before anything is written.
+    pub fn build_into<B: AsMut<[u8]>>(&self, mut data: B) -> Result<Quack<B>, OutaBounds> {
+        let plan = self.plan()?;
+        self.lay_out(&plan, data.as_mut())?;
+        Ok(Quack::new(data))
+    }
+
+    /// Lays out the quack in a freshly allocated, exact-sized buffer.
+    pub fn build(&self) -> Result<Quack<Vec<u8>>, OutaBounds> {
+        let plan = self.plan()?;
+        let size = usize::try_from(plan.size()?).map_err(|_| OutaBounds)?;
+        let mut data = vec![0u8; size];
+        self.lay_out(&plan, &mut data)?;
+        Ok(Quack::new(data))
+    }
+
+    /// Encodes every value and settles on a layout.
+    fn plan(&self) -> Result<Plan<'_>, OutaBounds> {
+        let sample_sizes: Vec<usize> = self.entries.iter().map(|entry| entry.len).collect();
+        let mut compressor = Compressor::new(self.compression, &self.values, &sample_sizes)?;
+        // Maps each distinct value to the first entry holding it.
+        let mut seen: HashMap<&[u8], usize> = HashMap::new();
+        let mut payloads = Vec::with_capacity(self.entries.len());
+        for (i, entry) in self.entries.iter().enumerate() {
+            let raw = &self.values[entry.start..entry.start + entry.len];
+            if self.dedup && raw.len() as u64 > SlotWidth::U64.bytes() {
+                let canonical = *seen.entry(raw).or_insert(i);
+                if canonical != i {
+                    payloads.push(Stored::Shared(canonical));
+                    continue;
+                }
+            }
+            let (payload, tags) = encode(&mut compressor, raw)?;
+            payloads.push(Stored::Inline(payload, tags));
+        }
+        let occupied = self.occupied_slots();
+
+        let mut plan = Plan {
+            layout: stor::Layout::new(Format::new(), self.num_slots),
+            occupied,
+            dictionary: compressor.dictionary().to_vec(),
+            payloads,
+        };
+        let layout = self.settle_layout(&plan.occupied, &plan.dictionary, |layout| {
+            plan.size_with(layout)
+        })?;
+        plan.layout = layout;
+        Ok(plan)
+    }
+
+    /// Picks the narrowest slot width that can address the whole buffer,
+    /// `size` bytes for a given layout.
+    fn settle_layout<F>(
+        &self,
+        occupied: &[u64],
+        dictionary: &[u8],
+        size: F,
+    ) -> Result<stor::Layout, OutaBounds>
+    where
+        F: Fn(&stor::Layout) -> Result<u64, OutaBounds>,
+    {
+        let mut layout = stor::Layout::new(Format::new(), self.num_slots);
+        layout.occupied_slots = occupied.len() as u64;
+        layout.dictionary_len = dictionary.len() as u64;
+        for slot_width in SlotWidth::ALL {
+            layout.format = Format::new()
+                .with_elements(self.elements)
+                .with_slot_width(slot_width)
+                .with_sparse_slots(self.sparse)
+                .with_shared_payloads(self.dedup)
+                .with_codec(self.compression.codec());
+            if !dictionary.is_empty() {
+                layout.dictionary_start = layout.store_start()?;
+            }
+            if SlotWidth::narrowest_for(size(&layout)?).bytes() <= slot_width.bytes() {
+                break;
+            }
+        }
+        Ok(layout)
+    }
+
+    /// Like [QuackBuilder::build], but encodes and lays out values on up to
+    /// `threads` threads. Slots are split into contiguous ranges holding about
+    /// as many value bytes each. Every thread lays out the chains of its range
+    /// in a region of its own, with offsets relative to that region, then a
+    /// single pass copies the regions into place and fixes up their offsets.
+    ///
+    /// Reads return the same as from [QuackBuilder::build]. The slot width is
+    /// picked before compressing so it may come out wider, and dedup only finds
+    /// repeats within a thread's range of slots.
+    pub fn build_parallel(&self, threads: usize) -> Result<Quack<Vec<u8>>, OutaBounds> {
+        let sample_sizes: Vec<usize> = self.entries.iter().map(|entry| entry.len).collect();
+        let compressor = Compressor::new(self.compression, &self.values, &sample_sizes)?;
+        let dictionary = compressor.dictionary();
+        let occupied = self.occupied_slots();
+        // Compression and dedup only ever shrink values, so sizing for the
+        // raw values gives a slot width wide enough for the result.
+        let layout = self.settle_layout(&occupied, dictionary, |layout| {
+            layout
+                .store_size(sample_sizes.iter().map(|len| *len as u64))?
+                .checked_add(dictionary.len() as u64)
+                .ok_or(OutaBounds)
+        })?;
+
+        let order = self.slot_order();
+        let ranges = self.split_slot_ranges(&order, threads.max(1));
+        let regions = std::thread::scope(|s| {
+            let handles: Vec<_> = ranges
+                .into_iter()
+                .map(|range| {
+                    let compressor = compressor.fork()?;
+                    Ok(s.spawn(move || self.lay_out_region(&layout, compressor, range)))
+                })
+                .collect::<Result<_, OutaBounds>>()?;
+            handles
+                .into_iter()
+                .map(|handle| handle.join().expect("region layout panicked"))
+                .collect::<Result<Vec<_>, _>>()
+        })?;
+
+        let store_start = layout.store_start()?;
+        let mut base = store_start
+            .checked_add(dictionary.len() as u64)
+            .ok_or(OutaBounds)?;
+        let size = regions
+            .iter()
+            .try_fold(base, |size, region| {
+                size.checked_add(region.data.len() as u64)
+            })
+            .ok_or(OutaBounds)?;
+        let mut dat = vec![0u8; usize::try_from(size).map_err(|_| OutaBounds)?];
+        layout.write(&mut dat)?;
+        if self.sparse {
+            layout.write_occupied(&mut dat, &occupied)?;
+        }
+        crate::write_range(&mut dat, store_start, dictionary)?;
+        for region in &regions {
+            crate::write_range(&mut dat, base, &region.data)?;
+            fix_up(&mut dat, &layout, base, region)?;
+            base += region.data.len() as u64;
+        }
+        layout.write_store_len(&mut dat, base - store_start)?;
+        Ok(Quack::new(dat))
+    }
+
+    /// Entry indices, sorted by slot. Sorting is stable so each slot's entries
+    /// stay in insertion order.
+    fn slot_order(&self) -> Vec<usize> {
+        let mut order: Vec<usize> = (0..self.entries.len()).collect();
+        order.sort_by_key(|&i| self.entries[i].slot);
+        order
+    }
+
+    /// Splits `order` into up to `parts` runs of whole slots, with about as
+    /// many value bytes in each.
+    fn split_slot_ranges<'o>(&self, order: &'o [usize], parts: usize) -> Vec<&'o [usize]> {
+        let total: usize = self.entries.iter().map(|entry| entry.len).sum();
+        let target = total.div_ceil(parts).max(1);
+        let mut ranges = Vec::with_capacity(parts);
+        let (mut start, mut bytes) = (0, 0);
+        for (i, &entry) in order.iter().enumerate() {
+            let slot_ends = order
+                .get(i + 1)
+                .is_none_or(|&next| self.entries[next].slot != self.entries[entry].slot);
+            bytes += self.entries[entry].len;
+            if slot_ends && bytes >= target && ranges.len() + 1 < parts {
+                ranges.push(&order[start..=i]);
+                (start, bytes) = (i + 1, 0);
+            }
+        }
+        if start < order.len() {
+            ranges.push(&order[start..]);
+        }
+        ranges
+    }
+
+    /// Lays out the chains of the slots in `range` as if the store started at
+    /// offset 0. No element is ever at offset 0 and pointed to by a next
+    /// pointer, since those always point forward, so 0 still ends a chain.
+    fn lay_out_region(
+        &self,
+        layout: &stor::Layout,
+        mut compressor: Compressor,
+        range: &[usize],
+    ) -> Result<Region, OutaBounds> {
+        let format = layout.format;
+        let width = format.slot_width().bytes();
+        let mut region = Region {
+            data: Vec::new(),
+            heads: Vec::new(),
+        };
+        // Maps each distinct value to the region offset of its first copy.
+        let mut seen: HashMap<&[u8], u64> = HashMap::new();
+        for chain in range.chunk_by(|&a, &b| self.entries[a].slot == self.entries[b].slot) {
+            region
+                .heads
+                .push((self.entries[chain[0]].slot, region.data.len() as u64));
+            for (i, &entry) in chain.iter().rev().enumerate() {
+                let entry = &self.entries[entry];
+                let raw = &self.values[entry.start..entry.start + entry.len];
+                let start = region.data.len() as u64;
+                let reference: [u8; 8];
+                let (payload, tags) = match seen.get(raw) {
+                    Some(canonical) => {
+                        reference = canonical.to_be_bytes();
+                        let tags = val::Tags {
+                            shared: true,
+                            ..val::Tags::default()
+                        };
+                        (Cow::Borrowed(&reference[8 - width as usize..]), tags)
+                    }
+                    None => {
+                        if self.dedup && raw.len() as u64 > SlotWidth::U64.bytes() {
+                            seen.insert(raw, start);
+                        }
+                        encode(&mut compressor, raw)?
+                    }
+                };
+                let end = start
+                    .checked_add(format.element_size(payload.len() as u64)?)
+                    .ok_or(OutaBounds)?;
+                let next = if i + 1 == chain.len() { 0 } else { end };
+                region.data.resize(end as usize, 0);
+                val::write(&mut region.data, format, start, next, &payload, tags)?;
+            }
+        }
+        Ok(region)
+    }
+
+    fn lay_out(&self, plan: &Plan, dat: &mut [u8]) -> Result<(), OutaBounds> {
+        let layout = plan.layout;
+        let width = layout.format.slot_width().bytes();
+        if (dat.len() as u64) < plan.size()? {
+            return Err(OutaBounds);
+        }
+
+        // reserved header fields too, so a reused buffer leaves nothing behind
+        let store_start = layout.store_start()?;
+        dat.get_mut(..store_start as usize)
+            .ok_or(OutaBounds)?
+            .fill(0);
+        layout.write(dat)?;
+        if self.sparse {
+            layout.write_occupied(dat, &plan.occupied)?;
+        }
+        crate::write_range(dat, store_start, &plan.dictionary)?;
+
+        let order = self.slot_order();
+        let chains = || order.chunk_by(|&a, &b| self.entries[a].slot == self.entries[b].slot);
+
+        // Shared payloads may refer forward, so place everything before writing.
+        let mut offsets = vec![0u64; self.entries.len()];
+        let mut offset = store_start
+            .checked_add(layout.dictionary_len)
+            .ok_or(OutaBounds)?;
+        for chain in chains() {
+            for &entry in chain.iter().rev() {
+                offsets[entry] = offset;
+                let size = layout
+                    .format
+                    .element_size(plan.payloads[entry].len(width))?;
+                offset = offset.checked_add(size).ok_or(OutaBounds)?;
+            }
+        }
+        layout.write_store_len(dat, offset - store_start)?;
+
+        for chain in chains() {
+            layout.write_slot(
+                dat,
+                self.entries[chain[0]].slot,
+                offsets[chain[chain.len() - 1]],
+            )?;
+            for (i, &entry) in chain.iter().rev().enumerate() {
+                let next = if i + 1 == chain.len() {
+                    0
+                } else {
+                    offsets[chain[chain.len() - 2 - i]]
+                };
+                let reference: [u8; 8];
+                let (payload, tags) = match &plan.payloads[entry] {
+                    Stored::Inline(payload, tags) => (&payload[..], *tags),
+                    Stored::Shared(canonical) => {
+                        reference = offsets[*canonical].to_be_bytes();
+                        let tags = val::Tags {
+                            shared: true,
+                            ..val::Tags::default()
+                        };
+                        (&reference[8 - width as usize..], tags)
+                    }
+                };
+                val::write(dat, layout.format, offsets[entry], next, payload, tags)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Sorted, deduplicated slots holding at least one entry. Only computed
+    /// for sparse builds, which are the only ones that care.
+    fn occupied_slots(&self) -> Vec<u64> {
+        if !self.sparse {
+            return Vec::new();
+        }
+        let mut slots: Vec<u64> = self.entries.iter().map(|entry| entry.slot).collect();
+        slots.sort_unstable();
+        slots.dedup();
+        slots
+    }
+}
+
+/// What [QuackBuilder::stats] reports.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct BuildStats {
+    pub entries: u64,
+    /// Total length of the inserted values.
+    pub value_bytes: u64,
+    /// Total length of the payloads actually stored, after compression and dedup.
+    pub stored_bytes: u64,
+    pub compressed_values: u64,
+    /// Entries stored as a reference to an identical value.
+    pub shared_values: u64,
+    /// Payload bytes dedup avoided storing, net of the references.
+    pub dedup_bytes_saved: u64,
+    pub dictionary_bytes: u64,
+}
+
+/// The stored form of `raw`, compressed if that saves space.
+fn encode<'a>(
+    compressor: &mut Compressor,
+    raw: &'a [u8],
+) -> Result<(Cow<'a, [u8]>, val::Tags), OutaBounds> {
+    Ok(match compressor.compress(raw)? {
+        Some(compressed) => (
+            Cow::Owned(compressed),
+            val::Tags {
+                compressed: true,
+                ..val::Tags::default()
+            },
+        ),
+        None => (Cow::Borrowed(raw), val::Tags::default()),
+    })
+}
+
+/// Chains of a range of slots laid out by one thread of
+/// [QuackBuilder::build_parallel], with offsets relative to the region.
+struct Region {
+    data: Vec<u8>,
+    /// Slot and region offset of the head of each chain.
+    heads: Vec<(u64, u64)>,
+}
+
+/// Moves the offsets in a region copied to `base` to where it ended up, and
+/// points its slots at it.
+fn fix_up(
+    dat: &mut [u8],
+    layout: &stor::Layout,
+    base: u64,
+    region: &Region,
+) -> Result<(), OutaBounds> {
+    let format = layout.format;
+    let width = format.slot_width().bytes();
+    let end = base + region.data.len() as u64;
+    let mut offset = base;
+    while offset < end {
+        let element = val::read(dat, format, offset)?;
+        let (next, shared, len) = (
+            element.next,
+            element.tags.shared,
+            element.payload.len() as u64,
+        );
+        // relative next pointers moved along with the region
+        if next != 0 && format.elements() != ElementEncoding::Relative {
+            val::write_next(dat, format, offset, next + base)?;
+        }
+        if shared {
+            let reference = offset + val::overhead(format, len)?;
+            let target = crate::read_uint(dat, reference, width)? + base;
+            crate::write_uint(dat, reference, width, target)?;
+        }
+        offset += format.element_size(len)?;
+    }
+    for (slot, head) in &region.heads {
+        layout.write_slot(dat, *slot, head + base)?;
+    }
+    Ok(())
+}
+
+/// Everything decided before any bytes are laid out.
+struct Plan<'a> {
+    layout: stor::Layout,
+    occupied: Vec<u64>,
+    dictionary: Vec<u8>,
+    /// Stored form of each entry's value, in insertion order.
+    payloads: Vec<Stored<'a>>,
+}
+
+enum Stored<'a> {
+    Inline(Cow<'a, [u8]>, val::Tags),
+    /// Same value as the entry with this index, which is stored inline.
+    Shared(usize),
+}
+
+impl Stored<'_> {
+    /// Payload length, given the slot width in bytes.
+    fn len(&self, width: u64) -> u64 {
+        match self {
+            Stored::Inline(payload, _) => payload.len() as u64,
+            Stored::Shared(_) => width,
+        }
+    }
+}
+
+impl Plan<'_> {
+    fn size(&self) -> Result<u64, OutaBounds> {
+        self.size_with(&self.layout)
+    }
+
+    fn size_with(&self, layout: &stor::Layout) -> Result<u64, OutaBounds> {
+        let width = layout.format.slot_width().bytes();
+        layout
+            .store_size(self.payloads.iter().map(|stored| stored.len(width)))?
+            .checked_add(self.dictionary.len() as u64)
+            .ok_or(OutaBounds)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_sequential_writes() {
+        let mut builder = QuackBuilder::new(3).elements(ElementEncoding::Compact);
+        let mut quack = Format::new()
+            .with_elements(ElementEncoding::Compact)
+            .initialize_assume_zeroed(vec![0u8; 1024], 3)
+            .unwrap();
+        for (k, v) in [(0, "a"), (4, "bb"), (3, "ccc"), (1, ""), (6, "dddd")] {
+            builder.insert(k, v.as_bytes()).unwrap();
+            quack.write(k, v.as_bytes()).unwrap();
+        }
+
+        let built = builder.build().unwrap();
+        assert_eq!(built.ref_inner().len() as u64, builder.size().unwrap());
+        assert_eq!(built.format().unwrap().slot_width(), SlotWidth::U32);
+        for k in 0..3 {
+            let expected = quack.read(k).unwrap().collect::<Vec<_>>();
+            assert_eq!(built.read(k).unwrap().collect::<Vec<_>>(), expected);
+        }
+
+        // a reused buffer comes out the same as a fresh one
+        let dirty = vec![0xffu8; built.ref_inner().len()];
+        let rebuilt = builder.build_into(dirty).unwrap();
+        assert_eq!(rebuilt.ref_inner(), built.ref_inner());
+    }
+
+    #[test]
+    fn sparse_slots() {
+        let mut dense = QuackBuilder::new(1000);
+        let mut sparse = QuackBuilder::new(1000).sparse_slots(true);
+        for (k, v) in [(5, "a"), (1005, "b"), (63, "c"), (64, "d"), (999, "e")] {
+            dense.insert(k, v.as_bytes()).unwrap();
+            sparse.insert(k, v.as_bytes()).unwrap();
+        }
+        assert!(sparse.size().unwrap() < dense.size().unwrap() / 2);
+
+        let dense = dense.build().unwrap();
+        let mut sparse = sparse.build().unwrap();
+        assert!(sparse.format().unwrap().sparse_slots());
+        for k in 0..1000 {
+            let expected = dense.read(k).unwrap().collect::<Vec<_>>();
+            assert_eq!(sparse.read(k).unwrap().collect::<Vec<_>>(), expected);
+        }
+
+        // occupied slots have room for more values, other slots don't
+        let mut grown = sparse.ref_inner().clone();
+        grown.resize(grown.len() + 64, 0);
+        sparse = Quack::new(grown);
+        sparse.write(63, b"f").unwrap();
+        assert_eq!(
+            sparse.read(63).unwrap().collect::<Vec<_>>(),
+            [&b"f"[..], b"c"]
+        );
+        assert!(sparse.write(62, b"g").is_err());
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn zstd_with_dictionary() {
+        let compression = Compression::Zstd {
+            level: 3,
+            dictionary_size: 4096,
+        };
+        let mut plain = QuackBuilder::new(64);
+        let mut compressed = QuackBuilder::new(64).compression(compression);
+        for k in 0..2000u64 {
+            let v = format!(r#"{{"id":{k},"kind":"duck","sound":"quack","legs":2}}"#);
+            plain.insert(k, v.as_bytes()).unwrap();
+            compressed.insert(k, v.as_bytes()).unwrap();
+        }
+        let plain = plain.build().unwrap();
+        let compressed = compressed.build().unwrap();
+        let layout = stor::Layout::read(compressed.ref_inner()).unwrap();
+        assert!(layout.dictionary_len > 0);
+        assert!(compressed.ref_inner().len() < plain.ref_inner().len() * 3 / 4);
+        assert_eq!(compressed.format().unwrap().codec(), crate::Codec::Zstd);
+
+        let mut buf = Vec::new();
+        for k in 0..64 {
+            let expected = plain.read(k).unwrap().collect::<Vec<_>>();
+            let decoded = compressed.read(k).unwrap().collect::<Vec<_>>();
+            assert_eq!(decoded, expected);
+
+            let mut seq = compressed.read(k).unwrap();
+            for expected in &expected {
+                assert!(seq.try_next_into(&mut buf).unwrap());
+                assert_eq!(buf, **expected);
+            }
+            assert!(!seq.try_next_into(&mut buf).unwrap());
+        }
+    }
+
+    #[cfg(not(feature = "lz4"))]
+    #[test]
+    fn missing_codec() {
+        let mut builder = QuackBuilder::new(2).compression(Compression::Lz4);
+        builder.insert(0, &[b'q'; 300]).unwrap();
+        assert!(builder.build().is_err());
+    }
+
+    #[cfg(feature = "lz4")]
+    #[test]
+    fn lz4_skips_incompressible() {
+        let mut builder = QuackBuilder::new(2).compression(Compression::Lz4);
+        builder.insert(0, &[b'q'; 300]).unwrap();
+        builder.insert(0, b"tiny").unwrap();
+        let quack = builder.build().unwrap();
+
+        let mut seq = quack.read(0).unwrap();
+        assert_eq!(seq.try_next().unwrap().unwrap(), b"tiny");
+        assert!(seq.try_next().unwrap().unwrap().len() < 300);
+        // iterating decodes every value, small and large alike
+        let items = quack.read(0).unwrap().collect::<Vec<_>>();
+        assert_eq!(items, [&b"tiny"[..], &[b'q'; 300][..]]);
+        let entries = quack.iter_entries().unwrap().map(|entry| entry.unwrap().1);
+        assert!(entries.eq(items));
+    }
+
+    #[test]
+    fn dedup() {
+        let big = [b'd'; 100];
+        let mut plain = QuackBuilder::new(4);
+        let mut dedup = QuackBuilder::new(4).dedup(true);
+        for (k, v) in [
+            (0, &big[..]),
+            (1, b"short"),
+            (2, &big),
+            (1, b"short"),
+            (0, &big),
+        ] {
+            plain.insert(k, v).unwrap();
+            dedup.insert(k, v).unwrap();
+        }
+
+        let stats = dedup.stats().unwrap();
+        assert_eq!(stats.shared_values, 2);
+        assert_eq!(stats.dedup_bytes_saved, 2 * (100 - 4));
+        assert_eq!(
+            stats.stored_bytes,
+            stats.value_bytes - stats.dedup_bytes_saved
+        );
+
+        let plain = plain.build().unwrap();
+        let dedup = dedup.build().unwrap();
+        assert!(dedup.format().unwrap().shared_payloads());
+        assert!(dedup.ref_inner().len() < plain.ref_inner().len() - 150);
+        for k in 0..4 {
+            let expected = plain.read(k).unwrap().collect::<Vec<_>>();
+            assert_eq!(dedup.read(k).unwrap().collect::<Vec<_>>(), expected);
+        }
+    }
+
+    #[test]
+    fn dedup_narrow_slots() {
+        let mut builder = QuackBuilder::new(4).dedup(true);
+        for v in [&b"sixsix"[..], b"sixsix", b"ninenine!", b"ninenine!"] {
+            builder.insert(0, v).unwrap();
+        }
+        let quack = builder.build().unwrap();
+        assert_eq!(quack.format().unwrap().slot_width(), SlotWidth::U32);
+
+        // wider than a slot, but no wider than 8 bytes, so still inline
+        let shared = quack
+            .scan_store()
+            .unwrap()
+            .map(|element| element.map(|e| (e.value, e.shared)))
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(shared.iter().filter(|(_, shared)| *shared).count(), 1);
+        assert!(shared.contains(&(b"ninenine!", true)));
+        assert!(!shared.contains(&(b"sixsix", true)));
+    }
+
+    #[test]
+    fn parallel_matches_sequential() {
+        let configs = [
+            QuackBuilder::new(50),
+            QuackBuilder::new(50).elements(ElementEncoding::Compact),
+            QuackBuilder::new(50)
+                .elements(ElementEncoding::Relative)
+                .dedup(true),
+            QuackBuilder::new(500).sparse_slots(true).dedup(true),
+            #[cfg(feature = "zstd")]
+            QuackBuilder::new(50).compression(Compression::Zstd {
+                level: 3,
+                dictionary_size: 1024,
+            }),
+        ];
+        for mut builder in configs {
+            for k in 0..3000u64 {
+                let v = format!("value {} of key {k}", k % 7).repeat(k as usize % 4);
+                builder.insert(k * 31 % 997, v.as_bytes()).unwrap();
+            }
+            let sequential = builder.build().unwrap();
+            for threads in [1, 3, 8] {
+                let parallel = builder.build_parallel(threads).unwrap();
+                assert_eq!(parallel.format().unwrap(), sequential.format().unwrap());
+                for k in 0..builder.num_slots() {
+                    let expected = sequential.read(k).unwrap().collect::<Vec<_>>();
+                    let items = parallel.read(k).unwrap().collect::<Vec<_>>();
+                    assert_eq!(items, expected);
+                }
+            }
+        }
+        assert!(
+            QuackBuilder::new(4)
+                .build_parallel(4)
+                .unwrap()
+                .read(0)
+                .unwrap()
+                .next()
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn slot_widths() {
+        let format = Format::new().with_slot_width(SlotWidth::U32);
+        let mut quack = format.initialize_assume_zeroed(vec![0u8; 256], 2).unwrap();
+        quack.write(0, b"near").unwrap();
+        assert_eq!(quack.read(0).unwrap().collect::<Vec<_>>(), [&b"near"[..]]);
+        assert_eq!(SlotWidth::narrowest_for(1 << 32), SlotWidth::U32);
+        assert_eq!(SlotWidth::narrowest_for((1 << 32) + 1), SlotWidth::U40);
+    }
+}
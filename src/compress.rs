@@ -0,0 +1,344 @@
+use std::borrow::Cow;
+#[cfg(feature = "zstd")]
+use std::sync::OnceLock;
+
+use crate::{Codec, OutaBounds, val};
+
+/// Compression [crate::QuackBuilder] applies to each value.
+///
+/// Values that don't shrink are stored as is, so compressing never costs more
+/// than the flag bit per element. Building with a codec needs its cargo
+/// feature, `lz4` or `zstd`, and fails without it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+#[non_exhaustive]
+pub enum Compression {
+    #[default]
+    None,
+    Lz4,
+    /// `dictionary_size` is the maximum size of a dictionary trained on the
+    /// inserted values and stored in the quack, 0 to skip training one.
+    /// Dictionaries pay off for many small, similar values.
+    Zstd {
+        level: i32,
+        dictionary_size: usize,
+    },
+}
+
+impl Compression {
+    pub fn codec(self) -> Codec {
+        match self {
+            Compression::None => Codec::None,
+            Compression::Lz4 => Codec::Lz4,
+            Compression::Zstd { .. } => Codec::Zstd,
+        }
+    }
+
+    /// Whether the cargo feature this compression needs is enabled.
+    pub fn is_available(self) -> bool {
+        match self {
+            Compression::None => true,
+            Compression::Lz4 => cfg!(feature = "lz4"),
+            Compression::Zstd { .. } => cfg!(feature = "zstd"),
+        }
+    }
+}
+
+/// Compresses values for one build.
+pub(crate) struct Compressor {
+    compression: Compression,
+    dictionary: Vec<u8>,
+    #[cfg(feature = "zstd")]
+    zstd: Option<zstd::bulk::Compressor<'static>>,
+}
+
+impl Compressor {
+    /// `samples` holds values back to back, with lengths `sample_sizes`. They
+    /// are used to train a dictionary if `compression` asks for one.
+    #[cfg_attr(not(feature = "zstd"), allow(unused_variables))]
+    pub fn new(
+        compression: Compression,
+        samples: &[u8],
+        sample_sizes: &[usize],
+    ) -> Result<Self, OutaBounds> {
+        #[cfg(feature = "zstd")]
+        if let Compression::Zstd {
+            dictionary_size, ..
+        } = compression
+        {
+            // Training fails when there is too little to learn from, in which
+            // case going without a dictionary is the best we can do anyway.
+            let dictionary = if dictionary_size == 0 {
+                Vec::new()
+            } else {
+                zstd::dict::from_continuous(samples, sample_sizes, dictionary_size)
+                    .unwrap_or_default()
+            };
+            return Self::with_dictionary(compression, dictionary);
+        }
+        Self::with_dictionary(compression, Vec::new())
+    }
+
+    /// A compressor using an already trained dictionary, or none if it is empty.
+    fn with_dictionary(compression: Compression, dictionary: Vec<u8>) -> Result<Self, OutaBounds> {
+        if !compression.is_available() {
+            return Err(OutaBounds);
+        }
+        #[cfg(feature = "zstd")]
+        if let Compression::Zstd { level, .. } = compression {
+            let zstd = zstd::bulk::Compressor::with_dictionary(level, &dictionary)
+                .map_err(|_| OutaBounds)?;
+            return Ok(Compressor {
+                compression,
+                dictionary,
+                zstd: Some(zstd),
+            });
+        }
+        Ok(Compressor {
+            compression,
+            dictionary,
+            #[cfg(feature = "zstd")]
+            zstd: None,
+        })
+    }
+
+    /// Another compressor with the same settings and dictionary, for use on
+    /// another thread.
+    pub fn fork(&self) -> Result<Self, OutaBounds> {
+        Self::with_dictionary(self.compression, self.dictionary.clone())
+    }
+
+    /// Dictionary to be stored alongside the values, empty if there is none.
+    pub fn dictionary(&self) -> &[u8] {
+        &self.dictionary
+    }
+
+    /// The compressed form of `raw`, or None if compressing doesn't save space.
+    pub fn compress(&mut self, raw: &[u8]) -> Result<Option<Vec<u8>>, OutaBounds> {
+        let Some(frame) = self.frame(raw)? else {
+            return Ok(None);
+        };
+        let (len, len_len) = val::leb128_bytes(raw.len() as u64);
+        if len_len + frame.len() >= raw.len() {
+            return Ok(None);
+        }
+        let mut stored = Vec::with_capacity(len_len + frame.len());
+        stored.extend_from_slice(&len[..len_len]);
+        stored.extend_from_slice(&frame);
+        Ok(Some(stored))
+    }
+
+    #[cfg_attr(not(any(feature = "lz4", feature = "zstd")), allow(unused_variables))]
+    fn frame(&mut self, raw: &[u8]) -> Result<Option<Vec<u8>>, OutaBounds> {
+        match self.compression {
+            Compression::None => Ok(None),
+            #[cfg(feature = "lz4")]
+            Compression::Lz4 => Ok(Some(lz4_flex::block::compress(raw))),
+            #[cfg(not(feature = "lz4"))]
+            Compression::Lz4 => Err(OutaBounds),
+            #[cfg(feature = "zstd")]
+            Compression::Zstd { .. } => self
+                .zstd
+                .as_mut()
+                .ok_or(OutaBounds)?
+                .compress(raw)
+                .map(Some)
+                .map_err(|_| OutaBounds),
+            #[cfg(not(feature = "zstd"))]
+            Compression::Zstd { .. } => Err(OutaBounds),
+        }
+    }
+}
+
+/// A quack's compression dictionary, prepared for decoding the first time a
+/// value needs it. Each quack keeps one and every reader borrows it, since
+/// preparing a dictionary costs far more than decoding a value with it.
+pub(crate) struct Dictionary {
+    #[cfg(feature = "zstd")]
+    zstd: OnceLock<zstd::dict::DecoderDictionary<'static>>,
+}
+
+/// For lists that never decode anything.
+pub(crate) static NO_DICTIONARY: Dictionary = Dictionary::new();
+
+impl Dictionary {
+    pub(crate) const fn new() -> Self {
+        Dictionary {
+            #[cfg(feature = "zstd")]
+            zstd: OnceLock::new(),
+        }
+    }
+
+    /// The dictionary prepared for zstd, from `bytes` if it isn't yet.
+    #[cfg(feature = "zstd")]
+    fn zstd<'b>(
+        &self,
+        bytes: impl FnOnce() -> Result<Cow<'b, [u8]>, OutaBounds>,
+    ) -> Result<&zstd::dict::DecoderDictionary<'static>, OutaBounds> {
+        if let Some(prepared) = self.zstd.get() {
+            return Ok(prepared);
+        }
+        let prepared = zstd::dict::DecoderDictionary::copy(&bytes()?);
+        Ok(self.zstd.get_or_init(|| prepared))
+    }
+}
+
+/// Decompresses the values of one quack with its [Dictionary].
+pub(crate) struct Decompressor<'a> {
+    #[cfg_attr(not(feature = "zstd"), allow(dead_code))]
+    dictionary: &'a Dictionary,
+    #[cfg(feature = "zstd")]
+    zstd: Option<zstd::bulk::Decompressor<'a>>,
+}
+
+impl Clone for Decompressor<'_> {
+    /// The clone sets up its own context when it first needs it.
+    fn clone(&self) -> Self {
+        Decompressor::new(self.dictionary)
+    }
+}
+
+impl<'a> Decompressor<'a> {
+    pub fn new(dictionary: &'a Dictionary) -> Self {
+        Decompressor {
+            dictionary,
+            #[cfg(feature = "zstd")]
+            zstd: None,
+        }
+    }
+
+    /// Decodes a compressed payload into `out`, replacing its contents.
+    /// `dictionary` loads the bytes of the quack's dictionary, which only
+    /// happens if it hasn't been prepared yet.
+    pub fn decompress_into<'b>(
+        &mut self,
+        codec: Codec,
+        dictionary: impl FnOnce() -> Result<Cow<'b, [u8]>, OutaBounds>,
+        stored: &[u8],
+        out: &mut Vec<u8>,
+    ) -> Result<(), OutaBounds> {
+        let (raw_len, len_len) = val::read_leb128(stored, 0)?;
+        let frame = &stored[len_len as usize..];
+        // don't trust a damaged length with an allocation the frame can't fill
+        if raw_len > max_decompressed_len(codec, frame) {
+            return Err(OutaBounds);
+        }
+        out.clear();
+        out.resize(usize::try_from(raw_len).map_err(|_| OutaBounds)?, 0);
+        if self.frame(codec, dictionary, frame, out)? != out.len() {
+            return Err(OutaBounds);
+        }
+        Ok(())
+    }
+
+    /// Returns the number of bytes written to `out`.
+    #[cfg_attr(not(all(feature = "lz4", feature = "zstd")), allow(unused_variables))]
+    fn frame<'b>(
+        &mut self,
+        codec: Codec,
+        dictionary: impl FnOnce() -> Result<Cow<'b, [u8]>, OutaBounds>,
+        frame: &[u8],
+        out: &mut [u8],
+    ) -> Result<usize, OutaBounds> {
+        match codec {
+            #[cfg(feature = "lz4")]
+            Codec::Lz4 => lz4_flex::block::decompress_into(frame, out).map_err(|_| OutaBounds),
+            #[cfg(feature = "zstd")]
+            Codec::Zstd => {
+                let decompressor = match &mut self.zstd {
+                    Some(decompressor) => decompressor,
+                    None => {
+                        let prepared = self.dictionary.zstd(dictionary)?;
+                        self.zstd.insert(
+                            zstd::bulk::Decompressor::with_prepared_dictionary(prepared)
+                                .map_err(|_| OutaBounds)?,
+                        )
+                    }
+                };
+                decompressor
+                    .decompress_to_buffer(frame, out)
+                    .map_err(|_| OutaBounds)
+            }
+            _ => Err(OutaBounds),
+        }
+    }
+}
+
+/// The most a frame of `codec` can decompress to.
+fn max_decompressed_len(codec: Codec, frame: &[u8]) -> u64 {
+    let len = frame.len() as u64;
+    match codec {
+        // every extra byte of a match length adds at most 255 bytes
+        Codec::Lz4 => len.saturating_mul(255),
+        // at best a 4 byte RLE block fills a whole 128 KiB block
+        Codec::Zstd => len.saturating_mul(128 * 1024 / 4),
+        Codec::None => 0,
+    }
+}
+
+#[cfg(all(test, any(feature = "lz4", feature = "zstd")))]
+mod tests {
+    use super::*;
+
+    #[cfg(feature = "lz4")]
+    #[test]
+    fn damaged_length() {
+        let raw = [b'q'; 300];
+        let mut compressor = Compressor::new(Compression::Lz4, &[], &[]).unwrap();
+        let stored = compressor.compress(&raw).unwrap().unwrap();
+        let mut decompressor = Decompressor::new(&NO_DICTIONARY);
+        let no_dictionary = || Ok(Cow::Borrowed(&[][..]));
+        let mut out = Vec::new();
+        decompressor
+            .decompress_into(Codec::Lz4, no_dictionary, &stored, &mut out)
+            .unwrap();
+        assert_eq!(out, raw);
+
+        // a length no frame this short could fill fails before allocating it
+        let (_, len_len) = val::leb128_bytes(raw.len() as u64);
+        let (huge, huge_len) = val::leb128_bytes(1 << 60);
+        let mut damaged = huge[..huge_len].to_vec();
+        damaged.extend_from_slice(&stored[len_len..]);
+        assert!(
+            decompressor
+                .decompress_into(Codec::Lz4, no_dictionary, &damaged, &mut out)
+                .is_err()
+        );
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn dictionary_prepared_once() {
+        let mut samples = Vec::new();
+        let mut sample_sizes = Vec::new();
+        for k in 0..1000 {
+            let v = format!(r#"{{"id":{k},"kind":"duck","sound":"quack"}}"#);
+            samples.extend_from_slice(v.as_bytes());
+            sample_sizes.push(v.len());
+        }
+        let compression = Compression::Zstd {
+            level: 3,
+            dictionary_size: 1024,
+        };
+        let mut compressor = Compressor::new(compression, &samples, &sample_sizes).unwrap();
+        assert!(!compressor.dictionary().is_empty());
+        let raw = br#"{"id":7,"kind":"duck","sound":"quack"}"#;
+        let stored = compressor.compress(raw).unwrap().unwrap();
+
+        // decompressors sharing a dictionary only load its bytes once
+        let dictionary = Dictionary::new();
+        let mut loads = 0;
+        let mut out = Vec::new();
+        for _ in 0..3 {
+            let mut decompressor = Decompressor::new(&dictionary);
+            let load = || {
+                loads += 1;
+                Ok(Cow::Borrowed(compressor.dictionary()))
+            };
+            decompressor
+                .decompress_into(Codec::Zstd, load, &stored, &mut out)
+                .unwrap();
+            assert_eq!(out, raw);
+        }
+        assert_eq!(loads, 1);
+    }
+}
@@ -0,0 +1,147 @@
+use std::io::{self, ErrorKind, Read, Write};
+
+use crate::overlay::{RECORD_HEADER_LEN, write_record};
+use crate::{OutaBounds, Quack, QuackBuilder, invalid_data, read_u64};
+
+/// Magic at the start of an export.
+const EXPORT_MAGIC: [u8; 8] = *b"QUACKEXP";
+const EXPORT_VERSION: u64 = 1;
+/// Magic, version, number of slots and number of records.
+const EXPORT_HEADER_LEN: usize = EXPORT_MAGIC.len() + 3 * size_of::<u64>();
+
+impl<B: AsRef<[u8]>> Quack<B> {
+    /// Writes every value to `writer` in a stream that doesn't depend on the
+    /// format the quack is stored in, for moving data between formats and
+    /// tools. Returns the number of values written.
+    ///
+    /// An export is a header, then one record per value. The header is the
+    /// magic b"QUACKEXP", then big-endian u64s: the format version, 1, the
+    /// number of slots and the number of records. Records are laid out like
+    /// [crate::Overlay] log records: a big-endian u64 key, a big-endian u64
+    /// value length, and the value, decoded. Quacks don't store keys, so the
+    /// key is the index of the value's slot. Records come in slot order, and
+    /// within a slot oldest first, so inserting them in order reproduces
+    /// the quack.
+    ///
+    /// The quack is checked with [Quack::verify] first, so a damaged quack
+    /// fails before anything is written.
+    pub fn export(&self, mut writer: impl Write) -> io::Result<u64> {
+        let snapshot = self.snapshot().map_err(invalid_data)?;
+        let entries = snapshot.verify().map_err(invalid_data)?;
+        let mut header = Vec::with_capacity(EXPORT_HEADER_LEN);
+        header.extend_from_slice(&EXPORT_MAGIC);
+        for word in [EXPORT_VERSION, snapshot.layout.num_slots, entries] {
+            header.extend_from_slice(&word.to_be_bytes());
+        }
+        writer.write_all(&header)?;
+
+        let mut values = Vec::new();
+        let mut record = Vec::new();
+        for slot in snapshot.iter_slots() {
+            let (slot, mut sequence) = slot.map_err(invalid_data)?;
+            while let Some(value) = sequence.try_next_decoded().map_err(invalid_data)? {
+                values.push(value);
+            }
+            for value in values.drain(..).rev() {
+                record.clear();
+                write_record(&mut record, slot, &value);
+                writer.write_all(&record)?;
+            }
+        }
+        Ok(entries)
+    }
+}
+
+impl Quack<Vec<u8>> {
+    /// Builds a quack from an export made by [Quack::export], with as many
+    /// slots as the exported quack had.
+    pub fn import(reader: impl Read) -> io::Result<Self> {
+        Self::import_with(reader, QuackBuilder::new)
+    }
+
+    /// Like [Quack::import], with `builder` making the builder to build with
+    /// from the exported number of slots, so the imported quack can be stored
+    /// in a different format.
+    pub fn import_with<F>(mut reader: impl Read, builder: F) -> io::Result<Self>
+    where
+        F: FnOnce(u64) -> QuackBuilder,
+    {
+        let mut header = [0u8; EXPORT_HEADER_LEN];
+        reader.read_exact(&mut header)?;
+        let word = |i: usize| read_u64(&header, (EXPORT_MAGIC.len() + 8 * i) as u64);
+        if header[..EXPORT_MAGIC.len()] != EXPORT_MAGIC
+            || word(0).map_err(invalid_data)? != EXPORT_VERSION
+        {
+            return Err(invalid_data(OutaBounds));
+        }
+        let num_slots = word(1).map_err(invalid_data)?;
+        let entries = word(2).map_err(invalid_data)?;
+
+        let mut builder = builder(num_slots);
+        if builder.num_slots() != num_slots {
+            return Err(invalid_data(OutaBounds));
+        }
+        let mut record_header = [0u8; RECORD_HEADER_LEN];
+        let mut value = Vec::new();
+        for _ in 0..entries {
+            reader.read_exact(&mut record_header)?;
+            let k = read_u64(&record_header, 0).map_err(invalid_data)?;
+            let len = read_u64(&record_header, 8).map_err(invalid_data)?;
+            value.clear();
+            // read through take, so a bogus length can't make us allocate it all up front
+            let read = reader.by_ref().take(len).read_to_end(&mut value)?;
+            if read as u64 != len {
+                return Err(ErrorKind::UnexpectedEof.into());
+            }
+            builder.insert(k, &value).map_err(invalid_data)?;
+        }
+        builder.build().map_err(invalid_data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ElementEncoding;
+
+    #[test]
+    fn round_trip() {
+        let mut quack = Quack::initialize_assume_zeroed(vec![0u8; 256], 3).unwrap();
+        quack.write(1, b"hello").unwrap();
+        quack.write(4, b"world").unwrap();
+        quack.write(0, b"quack").unwrap();
+
+        let mut exported = Vec::new();
+        assert_eq!(quack.export(&mut exported).unwrap(), 3);
+        let mut expected = b"QUACKEXP".to_vec();
+        for word in [1u64, 3, 3, 0, 5] {
+            expected.extend_from_slice(&word.to_be_bytes());
+        }
+        expected.extend_from_slice(b"quack");
+        for value in [b"hello", b"world"] {
+            expected.extend_from_slice(&1u64.to_be_bytes());
+            expected.extend_from_slice(&5u64.to_be_bytes());
+            expected.extend_from_slice(value);
+        }
+        assert_eq!(exported, expected);
+
+        let imported = Quack::import_with(&exported[..], |slots| {
+            QuackBuilder::new(slots).elements(ElementEncoding::Compact)
+        })
+        .unwrap();
+        assert_eq!(
+            imported.format().unwrap().elements(),
+            ElementEncoding::Compact
+        );
+        for k in 0..3 {
+            let expected = quack.read(k).unwrap().collect::<Vec<_>>();
+            assert_eq!(imported.read(k).unwrap().collect::<Vec<_>>(), expected);
+        }
+        let mut again = Vec::new();
+        imported.export(&mut again).unwrap();
+        assert_eq!(again, exported);
+
+        assert!(Quack::import(&exported[..exported.len() - 1]).is_err());
+        assert!(Quack::import_with(&exported[..], |_| QuackBuilder::new(2)).is_err());
+    }
+}
@@ -0,0 +1,154 @@
+use core::ops::Deref;
+
+use crate::{ElementEncoding, Format, OutaBounds, Quack, stor};
+
+impl Format {
+    /// An empty in-memory quack in this format, see [GrowingQuack::new].
+    ///
+    /// Fails for formats with concurrent access, as a growing vector is
+    /// reallocated with no promise of the alignment atomic access needs.
+    pub fn in_memory(&self, num_slots: u64) -> Result<GrowingQuack, OutaBounds> {
+        if self.concurrent_access() {
+            return Err(OutaBounds);
+        }
+        let layout = stor::Layout::new(*self, num_slots);
+        let len = usize::try_from(layout.store_start()?).map_err(|_| OutaBounds)?;
+        let quack = self.initialize_assume_zeroed(vec![0; len], num_slots)?;
+        Ok(GrowingQuack { quack })
+    }
+}
+
+/// A quack held in a vector that grows as it is written to, for tests and
+/// small tables where guessing the buffer size up front isn't worth it.
+///
+/// Reads go through the quack it derefs to. It wraps a `Quack<Vec<u8>>`
+/// rather than adding to it, as [Quack::write] is shared by every buffer type
+/// and can't grow vectors only.
+pub struct GrowingQuack {
+    quack: Quack<Vec<u8>>,
+}
+
+impl GrowingQuack {
+    /// An empty quack, sized to fit the header and slots.
+    ///
+    /// Uses [Format::LEGACY], see [Format::in_memory] for other formats.
+    pub fn new(num_slots: u64) -> Result<Self, OutaBounds> {
+        Format::LEGACY.in_memory(num_slots)
+    }
+
+    /// Like [Quack::write], but grows the buffer when the value doesn't fit,
+    /// at least doubling it each time so writes stay cheap on average. Only
+    /// fails if the buffer can't grow any further, as when the value would
+    /// end past the largest offset the format can link to, in which case the
+    /// buffer is left as it was.
+    pub fn write(&mut self, k: u64, v: &[u8]) -> Result<(), OutaBounds> {
+        let data = &mut self.quack.data;
+        let layout = stor::Layout::read(data)?;
+        let needed = layout
+            .store_start()?
+            .checked_add(layout.read_store_len(data)?)
+            .and_then(|end| end.checked_add(layout.format.element_size(v.len() as u64).ok()?))
+            .ok_or(OutaBounds)?;
+        let len = data.len() as u64;
+        if needed > len {
+            // Compact elements link to earlier ones by u32 offsets
+            let max_offset = match layout.format.elements() {
+                ElementEncoding::Compact => u32::MAX.into(),
+                _ => u64::MAX,
+            };
+            let limit = layout
+                .format
+                .slot_width()
+                .max_offset()
+                .min(max_offset)
+                .saturating_add(1);
+            if needed > limit {
+                return Err(OutaBounds);
+            }
+            let new_len = len.saturating_mul(2).min(limit).max(needed);
+            let new_len = usize::try_from(new_len).map_err(|_| OutaBounds)?;
+            data.resize(new_len, 0);
+        }
+        self.quack.write(k, v)
+    }
+
+    /// Drops the unused space at the end of the buffer, leaving it
+    /// store_start + store_len bytes long.
+    pub fn shrink_to_fit(&mut self) -> Result<(), OutaBounds> {
+        let data = &mut self.quack.data;
+        let layout = stor::Layout::read(data)?;
+        let end = layout
+            .store_start()?
+            .checked_add(layout.read_store_len(data)?)
+            .ok_or(OutaBounds)?;
+        let end = usize::try_from(end).map_err(|_| OutaBounds)?;
+        if end > data.len() {
+            return Err(OutaBounds);
+        }
+        data.truncate(end);
+        data.shrink_to_fit();
+        Ok(())
+    }
+
+    pub fn into_inner(self) -> Quack<Vec<u8>> {
+        self.quack
+    }
+}
+
+impl Deref for GrowingQuack {
+    type Target = Quack<Vec<u8>>;
+
+    fn deref(&self) -> &Quack<Vec<u8>> {
+        &self.quack
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SlotWidth;
+
+    #[test]
+    fn grows() {
+        let mut quack = GrowingQuack::new(4).unwrap();
+        assert_eq!(quack.capacity().unwrap(), 0);
+        for k in 0..100u64 {
+            quack.write(k, &k.to_be_bytes()).unwrap();
+        }
+        assert!(quack.remaining().unwrap() > 0);
+        quack.shrink_to_fit().unwrap();
+        assert_eq!(quack.remaining().unwrap(), 0);
+        assert_eq!(quack.ref_inner().len(), 16 + 4 * 8 + 100 * 24);
+        let items = quack.read(2).unwrap().take(2).collect::<Vec<_>>();
+        assert_eq!(items, [&98u64.to_be_bytes()[..], &94u64.to_be_bytes()]);
+        quack.write(0, b"").unwrap();
+
+        let format = Format::new().with_elements(ElementEncoding::Compact);
+        let mut quack = format.in_memory(1).unwrap();
+        quack.write(0, b"quack").unwrap();
+        assert_eq!(quack.read(0).unwrap().collect::<Vec<_>>(), [&b"quack"[..]]);
+
+        let concurrent = Format::new().with_concurrent_access(true);
+        assert!(concurrent.in_memory(1).is_err());
+    }
+
+    #[test]
+    fn past_largest_offset() {
+        for format in [
+            Format::new().with_slot_width(SlotWidth::U32),
+            Format::new().with_elements(ElementEncoding::Compact),
+        ] {
+            let mut quack = format.in_memory(1).unwrap();
+            quack.write(0, b"quack").unwrap();
+            // pretend the store already reaches just short of 4 GiB
+            let data = &mut quack.quack.data;
+            let layout = stor::Layout::read(data).unwrap();
+            let store_len = u64::from(u32::MAX) - layout.store_start().unwrap() - 8;
+            layout.write_store_len(data, store_len).unwrap();
+            let len = data.len();
+
+            assert!(quack.write(0, b"quack").is_err());
+            assert_eq!(quack.ref_inner().len(), len);
+        }
+    }
+}
@@ -1,75 +1,432 @@
 use core::{
     fmt::{self, Display, Formatter},
-    mem::size_of,
+    mem::{align_of, size_of},
+    sync::atomic::{AtomicU64, Ordering},
 };
+use std::borrow::Cow;
 use std::fmt::Debug;
+use std::sync::OnceLock;
 
-/// We store everything in one buffer. The layout is:
+mod builder;
+mod compress;
+mod export;
+mod growing;
+mod migrate;
+mod overlay;
+mod scan;
+mod shard;
+mod shared;
+mod sizing;
+mod stack;
+mod stats;
+mod transaction;
+mod verify;
+
+pub use builder::{BuildStats, QuackBuilder};
+pub use compress::Compression;
+pub use growing::GrowingQuack;
+pub use migrate::upgrade_legacy;
+pub use overlay::{Overlay, OverlaySequence};
+pub use scan::{Balance, Entries, Slots, StoreElement, StoreScan};
+pub use shard::{ShardManifest, ShardedBuilder, ShardedQuack};
+pub use shared::{AtomicBuffer, AtomicWords, SharedSequence, SharedSnapshot};
+pub use sizing::{SizingPlan, SizingTarget, ValueSizes};
+pub use stack::{QuackStack, StackSequence};
+pub use stats::QuackStats;
+pub use transaction::Transaction;
+
+/// We store everything in one buffer. The legacy layout is:
 /// [0..8):             u64 num_slots
 /// [8..16):            u64 store_len, serves as bump allocator state
 /// [16..num_slots+16): slots array
 /// [num_slots+16..):   store
 ///
-/// The store is bump-allocated storage for linked lists elements. Each element
-/// has this layout:
-/// [0..8):                    u64 next pointer
-/// [8..16):                   u64 payload length
-/// [16..payload_length + 16): payload data
+/// Versioned quacks instead start with a 64 byte header, followed by the
+/// slots array and the store. Slots are 4, 5, 6 or 8 byte big-endian offsets:
+/// [0..8):   magic, b"QUACKMAP"
+/// [8]:      u8 format version
+/// [9]:      u8 element encoding, see [ElementEncoding]
+/// [10]:     u8 slot width in bytes, see [SlotWidth]
+/// [11]:     u8 flags, see below
+/// [12]:     u8 codec values may be compressed with, see [Codec]
+/// [13..16): reserved
+/// [16..24): u64 num_slots
+/// [24..32): u64 store_len
+/// [32..40): u64 occupied slots, only used by sparse quacks
+/// [40..48): u64 offset of the compression dictionary, 0 if there is none
+/// [48..56): u64 length of the compression dictionary
+/// [56..64): reserved
+///
+/// Flags:
+/// bit 0: sparse, the slots array only holds offsets for occupied slots
+/// bit 1: shared, elements may refer to another element's payload
+/// bit 2: concurrent, see [Format::with_concurrent_access]
+/// bit 3: concurrent words are big-endian rather than little-endian
+///
+/// A sparse slots array starts with one 16 byte block per 64 slots:
+/// [0..8):   u64 bitmap, bit n set if slot 64 * block + n is occupied
+/// [8..16):  u64 number of occupied slots in all previous blocks
+/// followed by one offset per occupied slot, in slot order. Looking up a slot
+/// is a bitmap test plus a popcount away from its offset.
 ///
-/// u64s are stored big-endian
+/// Read as a legacy num_slots, the magic would describe a slots array far
+/// larger than any addressable buffer so the two layouts can't be confused.
+///
+/// The store is bump-allocated storage for linked lists elements, see [val]
+/// for the element layouts. A compression dictionary, if any, lives in the store
+/// too, outside of any list.
+///
+/// u64s are stored big-endian, except for the slots and store_len of
+/// concurrent quacks. Those are stored in the byte order of the machine
+/// that wrote them, so they can be accessed atomically.
 mod stor {
     use super::*;
 
-    pub const NUM_SLOTS_OFFSET: u64 = 0;
-    pub const STORE_LEN_OFFSET: u64 = size_of::<u64>() as u64;
-    pub const SLOTS_START: u64 = STORE_LEN_OFFSET + size_of::<u64>() as u64;
+    pub const MAGIC: [u8; 8] = *b"QUACKMAP";
+    pub const VERSION: u8 = 1;
 
-    pub fn read_num_slots(data: &[u8]) -> Result<u64, OutaBounds> {
-        super::read_u64(data, NUM_SLOTS_OFFSET)
-    }
+    pub const LEGACY_NUM_SLOTS_OFFSET: u64 = 0;
+    pub const LEGACY_STORE_LEN_OFFSET: u64 = size_of::<u64>() as u64;
+    pub const LEGACY_SLOTS_START: u64 = LEGACY_STORE_LEN_OFFSET + size_of::<u64>() as u64;
+
+    pub const VERSION_OFFSET: u64 = 8;
+    pub const ELEMENTS_OFFSET: u64 = 9;
+    pub const SLOT_WIDTH_OFFSET: u64 = 10;
+    pub const FLAGS_OFFSET: u64 = 11;
+    pub const CODEC_OFFSET: u64 = 12;
+    pub const NUM_SLOTS_OFFSET: u64 = 16;
+    pub const STORE_LEN_OFFSET: u64 = 24;
+    pub const OCCUPIED_SLOTS_OFFSET: u64 = 32;
+    pub const DICTIONARY_START_OFFSET: u64 = 40;
+    pub const DICTIONARY_LEN_OFFSET: u64 = 48;
+    pub const HEADER_LEN: u64 = 64;
+
+    pub const FLAG_SPARSE: u8 = 1 << 0;
+    pub const FLAG_SHARED: u8 = 1 << 1;
+    pub const FLAG_CONCURRENT: u8 = 1 << 2;
+    pub const FLAG_BIG_ENDIAN_WORDS: u8 = 1 << 3;
+    const KNOWN_FLAGS: u8 = FLAG_SPARSE | FLAG_SHARED | FLAG_CONCURRENT | FLAG_BIG_ENDIAN_WORDS;
+
+    const SLOTS_PER_BLOCK: u64 = u64::BITS as u64;
+    const BLOCK_LEN: u64 = 2 * size_of::<u64>() as u64;
 
-    pub fn read_store_len(data: &[u8]) -> Result<u64, OutaBounds> {
-        super::read_u64(data, STORE_LEN_OFFSET)
+    /// Everything needed to find things in a quack, as read from its header.
+    #[derive(Clone, Copy, Debug)]
+    pub struct Layout {
+        pub format: Format,
+        pub num_slots: u64,
+        /// Number of slots with room for an offset, only used by sparse quacks.
+        pub occupied_slots: u64,
+        /// Location of the compression dictionary, empty if there is none.
+        pub dictionary_start: u64,
+        pub dictionary_len: u64,
     }
 
-    pub fn write_store_len(data: &mut [u8], store_len: u64) -> Result<(), OutaBounds> {
-        super::write_u64(data, STORE_LEN_OFFSET, store_len)
+    impl Layout {
+        pub fn new(format: Format, num_slots: u64) -> Self {
+            Layout {
+                format,
+                num_slots,
+                occupied_slots: 0,
+                dictionary_start: 0,
+                dictionary_len: 0,
+            }
+        }
+
+        pub fn read(data: &[u8]) -> Result<Self, OutaBounds> {
+            if !is_versioned(data) {
+                return Ok(Layout::new(
+                    Format::LEGACY,
+                    super::read_u64(data, LEGACY_NUM_SLOTS_OFFSET)?,
+                ));
+            }
+            if read_u8(data, VERSION_OFFSET)? != VERSION {
+                return Err(OutaBounds);
+            }
+            let elements = ElementEncoding::from_tag(read_u8(data, ELEMENTS_OFFSET)?)?;
+            let slot_width = SlotWidth::from_bytes(read_u8(data, SLOT_WIDTH_OFFSET)?)?;
+            let flags = read_u8(data, FLAGS_OFFSET)?;
+            if flags & !KNOWN_FLAGS != 0 {
+                return Err(OutaBounds);
+            }
+            let sparse = flags & FLAG_SPARSE != 0;
+            let shared = flags & FLAG_SHARED != 0;
+            let concurrent = flags & FLAG_CONCURRENT != 0;
+            if concurrent && (flags & FLAG_BIG_ENDIAN_WORDS != 0) != cfg!(target_endian = "big") {
+                return Err(OutaBounds);
+            }
+            let codec = Codec::from_tag(read_u8(data, CODEC_OFFSET)?)?;
+            Ok(Layout {
+                format: Format::new()
+                    .with_elements(elements)
+                    .with_slot_width(slot_width)
+                    .with_sparse_slots(sparse)
+                    .with_shared_payloads(shared)
+                    .with_concurrent_access(concurrent)
+                    .with_codec(codec),
+                num_slots: super::read_u64(data, NUM_SLOTS_OFFSET)?,
+                occupied_slots: if sparse {
+                    super::read_u64(data, OCCUPIED_SLOTS_OFFSET)?
+                } else {
+                    0
+                },
+                dictionary_start: super::read_u64(data, DICTIONARY_START_OFFSET)?,
+                dictionary_len: super::read_u64(data, DICTIONARY_LEN_OFFSET)?,
+            })
+        }
+
+        /// Writes a fresh header, with an empty store.
+        pub fn write(&self, data: &mut [u8]) -> Result<(), OutaBounds> {
+            if self.format.versioned {
+                write_range(data, 0, &MAGIC)?;
+                write_u8(data, VERSION_OFFSET, VERSION)?;
+                write_u8(data, ELEMENTS_OFFSET, self.format.elements.tag())?;
+                write_u8(
+                    data,
+                    SLOT_WIDTH_OFFSET,
+                    self.format.slot_width.bytes() as u8,
+                )?;
+                write_u8(data, FLAGS_OFFSET, self.format.flags())?;
+                write_u8(data, CODEC_OFFSET, self.format.codec.tag())?;
+                if self.format.sparse {
+                    super::write_u64(data, OCCUPIED_SLOTS_OFFSET, self.occupied_slots)?;
+                }
+                super::write_u64(data, DICTIONARY_START_OFFSET, self.dictionary_start)?;
+                super::write_u64(data, DICTIONARY_LEN_OFFSET, self.dictionary_len)?;
+            }
+            super::write_u64(data, self.num_slots_offset(), self.num_slots)?;
+            self.write_store_len(data, 0)
+        }
+
+        fn num_slots_offset(&self) -> u64 {
+            if self.format.versioned {
+                NUM_SLOTS_OFFSET
+            } else {
+                LEGACY_NUM_SLOTS_OFFSET
+            }
+        }
+
+        pub fn store_len_offset(&self) -> u64 {
+            if self.format.versioned {
+                STORE_LEN_OFFSET
+            } else {
+                LEGACY_STORE_LEN_OFFSET
+            }
+        }
+
+        pub fn slots_start(&self) -> u64 {
+            self.format.header_len()
+        }
+
+        pub fn read_store_len(&self, data: &[u8]) -> Result<u64, OutaBounds> {
+            self.read_word(data, self.store_len_offset(), size_of::<u64>() as u64)
+        }
+
+        pub fn write_store_len(&self, data: &mut [u8], store_len: u64) -> Result<(), OutaBounds> {
+            let width = size_of::<u64>() as u64;
+            self.write_word(data, self.store_len_offset(), width, store_len)
+        }
+
+        pub fn read_slot(&self, data: &[u8], slot_index: u64) -> Result<u64, OutaBounds> {
+            match self.slot_offset(data, slot_index)? {
+                Some(offset) => self.read_word(data, offset, self.format.slot_width.bytes()),
+                None => Ok(0),
+            }
+        }
+
+        /// Fails for slots a sparse quack has no room for.
+        pub fn write_slot(
+            &self,
+            data: &mut [u8],
+            slot_index: u64,
+            value: u64,
+        ) -> Result<(), OutaBounds> {
+            let offset = self.slot_offset(data, slot_index)?.ok_or(OutaBounds)?;
+            self.write_word(data, offset, self.format.slot_width.bytes(), value)
+        }
+
+        /// Reads a slot or store_len, with acquire ordering for concurrent quacks.
+        fn read_word(&self, data: &[u8], start: u64, width: u64) -> Result<u64, OutaBounds> {
+            if self.format.concurrent {
+                super::load_acquire(data, start)
+            } else {
+                super::read_uint(data, start, width)
+            }
+        }
+
+        /// Writes a slot or store_len, with release ordering for concurrent quacks.
+        fn write_word(
+            &self,
+            data: &mut [u8],
+            start: u64,
+            width: u64,
+            value: u64,
+        ) -> Result<(), OutaBounds> {
+            if self.format.concurrent {
+                super::store_release(data, start, value)
+            } else {
+                super::write_uint(data, start, width, value)
+            }
+        }
+
+        /// Where the given slot's offset is stored, if anywhere.
+        pub fn slot_offset(&self, data: &[u8], slot_index: u64) -> Result<Option<u64>, OutaBounds> {
+            if slot_index >= self.num_slots {
+                return Err(OutaBounds);
+            }
+            let index = if self.format.sparse {
+                let block_start = self.block_start(slot_index / SLOTS_PER_BLOCK)?;
+                let bitmap = super::read_u64(data, block_start)?;
+                let bit = 1u64 << (slot_index % SLOTS_PER_BLOCK);
+                if bitmap & bit == 0 {
+                    return Ok(None);
+                }
+                let rank_before = super::read_u64(data, block_start + size_of::<u64>() as u64)?;
+                rank_before
+                    .checked_add((bitmap & (bit - 1)).count_ones().into())
+                    .ok_or(OutaBounds)?
+            } else {
+                slot_index
+            };
+            index
+                .checked_mul(self.format.slot_width.bytes())
+                .ok_or(OutaBounds)?
+                .checked_add(self.offsets_start()?)
+                .ok_or(OutaBounds)
+                .map(Some)
+        }
+
+        /// Marks `slots`, which must be sorted and deduplicated, as the
+        /// occupied slots of a sparse quack.
+        pub fn write_occupied(&self, data: &mut [u8], slots: &[u64]) -> Result<(), OutaBounds> {
+            if !self.format.sparse || slots.len() as u64 != self.occupied_slots {
+                return Err(OutaBounds);
+            }
+            let mut rank = 0u64;
+            let mut slots = slots.iter().peekable();
+            for block in 0..self.num_blocks() {
+                let block_start = self.block_start(block)?;
+                let mut bitmap = 0u64;
+                while let Some(&&slot) = slots.peek() {
+                    if slot / SLOTS_PER_BLOCK != block {
+                        break;
+                    }
+                    bitmap |= 1 << (slot % SLOTS_PER_BLOCK);
+                    slots.next();
+                }
+                super::write_u64(data, block_start, bitmap)?;
+                super::write_u64(data, block_start + size_of::<u64>() as u64, rank)?;
+                rank += u64::from(bitmap.count_ones());
+            }
+            if slots.next().is_some() {
+                return Err(OutaBounds);
+            }
+            Ok(())
+        }
+
+        fn num_blocks(&self) -> u64 {
+            self.num_slots.div_ceil(SLOTS_PER_BLOCK)
+        }
+
+        fn block_start(&self, block: u64) -> Result<u64, OutaBounds> {
+            block
+                .checked_mul(BLOCK_LEN)
+                .and_then(|block_offset| self.slots_start().checked_add(block_offset))
+                .ok_or(OutaBounds)
+        }
+
+        /// Start of the offsets stored in the slots array.
+        fn offsets_start(&self) -> Result<u64, OutaBounds> {
+            if self.format.sparse {
+                self.block_start(self.num_blocks())
+            } else {
+                Ok(self.slots_start())
+            }
+        }
+
+        /// Buffer size needed to hold values of the given sizes.
+        pub fn store_size<T>(&self, value_sizes: T) -> Result<u64, OutaBounds>
+        where
+            T: IntoIterator<Item = u64>,
+        {
+            value_sizes
+                .into_iter()
+                .try_fold(self.store_start()?, |acc, size| {
+                    acc.checked_add(self.format.element_size(size)?)
+                        .ok_or(OutaBounds)
+                })
+        }
+
+        pub fn dictionary<'a>(&self, data: &'a [u8]) -> Result<&'a [u8], OutaBounds> {
+            get_range_dynamic(data, self.dictionary_start, self.dictionary_len)
+        }
+
+        pub fn store_start(&self) -> Result<u64, OutaBounds> {
+            let stored_offsets = if self.format.sparse {
+                self.occupied_slots
+            } else {
+                self.num_slots
+            };
+            stored_offsets
+                .checked_mul(self.format.slot_width.bytes())
+                .and_then(|offsets_byte_size| {
+                    self.offsets_start().ok()?.checked_add(offsets_byte_size)
+                })
+                .ok_or(OutaBounds)
+        }
     }
 
-    pub fn write_num_slots(data: &mut [u8], num_slots: u64) -> Result<(), OutaBounds> {
-        super::write_u64(data, NUM_SLOTS_OFFSET, num_slots)
+    pub fn is_versioned(data: &[u8]) -> bool {
+        data.get(..MAGIC.len()) == Some(&MAGIC[..])
     }
 
-    pub fn read_slot(data: &[u8], slot_index: u64) -> Result<u64, OutaBounds> {
-        let slot_offset = slot_index
-            .checked_mul(size_of::<u64>() as u64)
-            .ok_or(OutaBounds)?
-            .checked_add(SLOTS_START)
-            .ok_or(OutaBounds)?;
-        super::read_u64(data, slot_offset)
+    pub fn read_num_slots(data: &[u8]) -> Result<u64, OutaBounds> {
+        Ok(Layout::read(data)?.num_slots)
     }
 
-    pub fn write_slot(data: &mut [u8], slot_index: u64, value: u64) -> Result<(), OutaBounds> {
-        let slot_offset = slot_index
-            .checked_mul(size_of::<u64>() as u64)
-            .ok_or(OutaBounds)?
-            .checked_add(SLOTS_START)
-            .ok_or(OutaBounds)?;
-        super::write_u64(data, slot_offset, value)
+    #[cfg(test)]
+    pub fn write_store_len(data: &mut [u8], store_len: u64) -> Result<(), OutaBounds> {
+        let offset = if is_versioned(data) {
+            STORE_LEN_OFFSET
+        } else {
+            LEGACY_STORE_LEN_OFFSET
+        };
+        super::write_u64(data, offset, store_len)
     }
 
-    pub fn store_start(num_slots: u64) -> Result<u64, OutaBounds> {
-        num_slots
-            .checked_mul(size_of::<u64>() as u64)
-            .and_then(|slots_byte_size| SLOTS_START.checked_add(slots_byte_size))
-            .ok_or(OutaBounds)
+    #[cfg(test)]
+    pub fn write_num_slots(data: &mut [u8], num_slots: u64) -> Result<(), OutaBounds> {
+        let offset = if is_versioned(data) {
+            NUM_SLOTS_OFFSET
+        } else {
+            LEGACY_NUM_SLOTS_OFFSET
+        };
+        super::write_u64(data, offset, num_slots)
     }
 }
 
-/// Values stored in the store. Each is a linked list. Layout:
+/// Values stored in the store. Each is a linked list.
+///
+/// [ElementEncoding::Wide] layout:
 /// [0..8):                    u64 next pointer
 /// [8..16):                   u64 payload length
 /// [16..payload_length + 16): payload data
+///
+/// [ElementEncoding::Compact] layout:
+/// [0..4):                    u32 next pointer
+/// [4..4 + n):                LEB128 payload length, n is 1 to 10 bytes
+/// [4 + n..):                 payload data
+///
+/// [ElementEncoding::Relative] is laid out like Compact, with the next pointer
+/// an i32 distance from the element's start, 0 for none.
+///
+/// Formats with a [Codec] store a flag in the low bit of the payload length,
+/// set if the payload is compressed. A compressed payload is the LEB128
+/// length of the original value followed by the codec's output.
+///
+/// Formats with shared payloads store another flag in the next bit up, set if
+/// the payload is the offset of another element, one slot width wide. That
+/// element's payload and compressed flag stand in for this one's.
 mod val {
     use super::*;
 
@@ -77,46 +434,656 @@ mod val {
     pub const PAYLOAD_LEN_OFFSET: u64 = size_of::<u64>() as u64;
     pub const PAYLOAD_START: u64 = PAYLOAD_LEN_OFFSET + size_of::<u64>() as u64;
 
-    pub fn write(data: &mut [u8], start: u64, next: u64, payload: &[u8]) -> Result<(), OutaBounds> {
-        write_u64(
-            data,
-            val::NEXT_POINTER_OFFSET
-                .checked_add(start)
-                .ok_or(OutaBounds)?,
-            next,
-        )?;
-        write_u64(
-            data,
-            val::PAYLOAD_LEN_OFFSET
-                .checked_add(start)
-                .ok_or(OutaBounds)?,
-            payload.len() as u64,
-        )?;
-        write_range(
-            data,
-            val::PAYLOAD_START.checked_add(start).ok_or(OutaBounds)?,
+    pub const COMPACT_PAYLOAD_LEN_OFFSET: u64 = size_of::<u32>() as u64;
+
+    /// The most bookkeeping any element has in front of its payload.
+    pub const MAX_OVERHEAD: usize = PAYLOAD_START as usize;
+
+    /// Per element flags, packed into the payload length.
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+    pub struct Tags {
+        pub compressed: bool,
+        pub shared: bool,
+    }
+
+    impl Tags {
+        fn bits(format: Format) -> u32 {
+            u32::from(format.codec() != Codec::None) + u32::from(format.shared_payloads())
+        }
+
+        fn pack(self, format: Format, payload_len: u64) -> Result<u64, OutaBounds> {
+            let bits = Self::bits(format);
+            if payload_len.leading_zeros() < bits {
+                return Err(OutaBounds);
+            }
+            let mut packed = payload_len << bits;
+            let mut bit = 0;
+            for (enabled, set) in [
+                (format.codec() != Codec::None, self.compressed),
+                (format.shared_payloads(), self.shared),
+            ] {
+                if set && !enabled {
+                    return Err(OutaBounds);
+                }
+                if enabled {
+                    packed |= u64::from(set) << bit;
+                    bit += 1;
+                }
+            }
+            Ok(packed)
+        }
+
+        fn unpack(format: Format, packed: u64) -> (Self, u64) {
+            let mut bit = 0;
+            let mut flag = |enabled: bool| {
+                let set = enabled && packed >> bit & 1 != 0;
+                bit += u32::from(enabled);
+                set
+            };
+            let tags = Tags {
+                compressed: flag(format.codec() != Codec::None),
+                shared: flag(format.shared_payloads()),
+            };
+            (tags, packed >> Self::bits(format))
+        }
+    }
+
+    /// An element as read from the store.
+    pub struct Element<'a> {
+        pub next: u64,
+        pub payload: &'a [u8],
+        pub tags: Tags,
+    }
+
+    /// The bookkeeping in front of an element's payload.
+    pub struct Header {
+        pub next: u64,
+        pub tags: Tags,
+        /// Where the payload starts, counted from the start of the element.
+        pub payload_start: u64,
+        pub payload_len: u64,
+    }
+
+    /// Bytes of bookkeeping stored in front of a payload of the given length.
+    pub fn overhead(format: Format, payload_len: u64) -> Result<u64, OutaBounds> {
+        Ok(match format.elements() {
+            ElementEncoding::Wide => PAYLOAD_START,
+            ElementEncoding::Compact | ElementEncoding::Relative => {
+                let packed = Tags::default().pack(format, payload_len)?;
+                COMPACT_PAYLOAD_LEN_OFFSET + leb128_len(packed)
+            }
+        })
+    }
+
+    pub fn write(
+        data: &mut [u8],
+        format: Format,
+        start: u64,
+        next: u64,
+        payload: &[u8],
+        tags: Tags,
+    ) -> Result<(), OutaBounds> {
+        let (header, header_len) = header(format, start, next, payload.len() as u64, tags)?;
+        write_range(data, start, &header[..header_len])?;
+        let payload_start = start.checked_add(header_len as u64).ok_or(OutaBounds)?;
+        write_range(data, payload_start, payload)
+    }
+
+    /// The bookkeeping in front of the payload of an element at `start`, and
+    /// how many bytes of it there are.
+    pub fn header(
+        format: Format,
+        start: u64,
+        next: u64,
+        payload_len: u64,
+        tags: Tags,
+    ) -> Result<([u8; MAX_OVERHEAD], usize), OutaBounds> {
+        let packed_len = tags.pack(format, payload_len)?;
+        let mut header = [0; MAX_OVERHEAD];
+        let (next, next_len) = next_bytes(format, start, next)?;
+        header[..next_len].copy_from_slice(&next[..next_len]);
+        let header_len = match format.elements() {
+            ElementEncoding::Wide => {
+                header[PAYLOAD_LEN_OFFSET as usize..PAYLOAD_START as usize]
+                    .copy_from_slice(&packed_len.to_be_bytes());
+                PAYLOAD_START as usize
+            }
+            ElementEncoding::Compact | ElementEncoding::Relative => {
+                let len_start = COMPACT_PAYLOAD_LEN_OFFSET as usize;
+                let (len, len_len) = leb128_bytes(packed_len);
+                header[len_start..len_start + len_len].copy_from_slice(&len[..len_len]);
+                len_start + len_len
+            }
+        };
+        Ok((header, header_len))
+    }
+
+    /// Points an already written element at a new next element.
+    pub fn write_next(
+        data: &mut [u8],
+        format: Format,
+        start: u64,
+        next: u64,
+    ) -> Result<(), OutaBounds> {
+        let next_start = NEXT_POINTER_OFFSET.checked_add(start).ok_or(OutaBounds)?;
+        let (next, next_len) = next_bytes(format, start, next)?;
+        write_range(data, next_start, &next[..next_len])
+    }
+
+    /// The next pointer of an element at `start`, as stored, and how many
+    /// bytes of it there are.
+    pub fn next_bytes(
+        format: Format,
+        start: u64,
+        next: u64,
+    ) -> Result<([u8; 8], usize), OutaBounds> {
+        let mut bytes = [0; 8];
+        let next = match format.elements() {
+            ElementEncoding::Wide => return Ok((next.to_be_bytes(), bytes.len())),
+            ElementEncoding::Compact => u32::try_from(next).map_err(|_| OutaBounds)?.to_be_bytes(),
+            ElementEncoding::Relative => {
+                let distance = if next == 0 {
+                    0
+                } else {
+                    i32::try_from(i128::from(next) - i128::from(start))
+                        .ok()
+                        .filter(|distance| *distance != 0)
+                        .ok_or(OutaBounds)?
+                };
+                distance.to_be_bytes()
+            }
+        };
+        bytes[..next.len()].copy_from_slice(&next);
+        Ok((bytes, next.len()))
+    }
+
+    pub fn read(data: &[u8], format: Format, start: u64) -> Result<Element<'_>, OutaBounds> {
+        let element = usize::try_from(start)
+            .ok()
+            .and_then(|start| data.get(start..))
+            .ok_or(OutaBounds)?;
+        let header = read_header(element, format, start)?;
+        let payload = get_range_dynamic(element, header.payload_start, header.payload_len)?;
+        Ok(Element {
+            next: header.next,
             payload,
-        )?;
-        Ok(())
+            tags: header.tags,
+        })
+    }
+
+    /// Parses the bookkeeping of the element at `start`. `element` holds the
+    /// buffer from `start` on, at least [MAX_OVERHEAD] bytes of it if there
+    /// are that many.
+    pub fn read_header(element: &[u8], format: Format, start: u64) -> Result<Header, OutaBounds> {
+        let (next, packed_len, payload_start) = match format.elements() {
+            ElementEncoding::Wide => (
+                read_u64(element, NEXT_POINTER_OFFSET)?,
+                read_u64(element, PAYLOAD_LEN_OFFSET)?,
+                PAYLOAD_START,
+            ),
+            ElementEncoding::Compact | ElementEncoding::Relative => {
+                let next = *get_range::<4>(element, NEXT_POINTER_OFFSET)?;
+                let next = match format.elements() {
+                    ElementEncoding::Relative => match i32::from_be_bytes(next) {
+                        0 => 0,
+                        distance => start
+                            .checked_add_signed(distance.into())
+                            .ok_or(OutaBounds)?,
+                    },
+                    _ => u32::from_be_bytes(next).into(),
+                };
+                let (packed_len, len_len) = read_leb128(element, COMPACT_PAYLOAD_LEN_OFFSET)?;
+                (next, packed_len, COMPACT_PAYLOAD_LEN_OFFSET + len_len)
+            }
+        };
+        let (tags, payload_len) = Tags::unpack(format, packed_len);
+        Ok(Header {
+            next,
+            tags,
+            payload_start,
+            payload_len,
+        })
+    }
+
+    /// Like [read], but an element with a shared payload comes back with the
+    /// payload and tags of the element it refers to.
+    pub fn resolve(data: &[u8], format: Format, start: u64) -> Result<Element<'_>, OutaBounds> {
+        let element = read(data, format, start)?;
+        if !element.tags.shared {
+            return Ok(element);
+        }
+        let width = format.slot_width().bytes();
+        if element.payload.len() as u64 != width {
+            return Err(OutaBounds);
+        }
+        let target = read(data, format, read_uint(element.payload, 0, width)?)?;
+        if target.tags.shared {
+            return Err(OutaBounds);
+        }
+        Ok(Element {
+            next: element.next,
+            ..target
+        })
+    }
+
+    pub fn leb128_len(mut value: u64) -> u64 {
+        let mut len = 1;
+        while value >= 0x80 {
+            value >>= 7;
+            len += 1;
+        }
+        len
+    }
+
+    /// Returns the encoded bytes, and how many of them are used.
+    pub fn leb128_bytes(mut value: u64) -> ([u8; 10], usize) {
+        let mut buf = [0u8; 10];
+        let mut len = 0;
+        loop {
+            let byte = (value & 0x7f) as u8;
+            value >>= 7;
+            if value == 0 {
+                buf[len] = byte;
+                len += 1;
+                break;
+            }
+            buf[len] = byte | 0x80;
+            len += 1;
+        }
+        (buf, len)
+    }
+
+    /// Returns the decoded value and the number of bytes it occupied.
+    pub fn read_leb128(data: &[u8], start: u64) -> Result<(u64, u64), OutaBounds> {
+        let mut value = 0u64;
+        for i in 0..10 {
+            let byte = *data
+                .get(start.checked_add(i).ok_or(OutaBounds)? as usize)
+                .ok_or(OutaBounds)?;
+            let bits = u64::from(byte & 0x7f);
+            let shift = 7 * i as u32;
+            if bits.checked_shl(shift).map(|v| v >> shift) != Some(bits) {
+                return Err(OutaBounds);
+            }
+            value |= bits << shift;
+            if byte & 0x80 == 0 {
+                return Ok((value, i + 1));
+            }
+        }
+        Err(OutaBounds)
+    }
+}
+
+/// How elements of the store encode their next pointer and payload length.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum ElementEncoding {
+    /// u64 next pointer and u64 payload length, 16 bytes of overhead per element.
+    #[default]
+    Wide,
+    /// u32 next pointer and LEB128 payload length, 5 bytes of overhead while the
+    /// packed length is under 128. Formats with a [Codec] or shared payloads pack
+    /// a flag bit each below the length, halving that limit per flag, down to
+    /// payloads under 32 bytes with both. Next pointers can only address the first
+    /// 4 GiB of the buffer, writes that would need to link to an element past that
+    /// fail with [OutaBounds].
+    Compact,
+    /// Like [ElementEncoding::Compact], but the next pointer is an i32 distance
+    /// from the element, so the buffer can be any size as long as linked
+    /// elements are within 2 GiB of each other. Built quacks store each slot's
+    /// elements side by side, so that always holds for them.
+    Relative,
+}
+
+impl ElementEncoding {
+    fn tag(self) -> u8 {
+        match self {
+            ElementEncoding::Wide => 0,
+            ElementEncoding::Compact => 1,
+            ElementEncoding::Relative => 2,
+        }
+    }
+
+    fn from_tag(tag: u8) -> Result<Self, OutaBounds> {
+        match tag {
+            0 => Ok(ElementEncoding::Wide),
+            1 => Ok(ElementEncoding::Compact),
+            2 => Ok(ElementEncoding::Relative),
+            _ => Err(OutaBounds),
+        }
+    }
+}
+
+/// Width of the offsets stored in the slots array. Narrower slots make for a
+/// smaller slots array but limit how large the buffer can be, writes that would
+/// need a slot to point past the limit fail with [OutaBounds].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum SlotWidth {
+    /// 4 bytes, buffers up to 4 GiB.
+    U32,
+    /// 5 bytes, buffers up to 1 TiB.
+    U40,
+    /// 6 bytes, buffers up to 256 TiB.
+    U48,
+    /// 8 bytes, no practical limit.
+    #[default]
+    U64,
+}
+
+impl SlotWidth {
+    /// All widths, narrowest first.
+    pub const ALL: [SlotWidth; 4] = [
+        SlotWidth::U32,
+        SlotWidth::U40,
+        SlotWidth::U48,
+        SlotWidth::U64,
+    ];
+
+    pub const fn bytes(self) -> u64 {
+        match self {
+            SlotWidth::U32 => 4,
+            SlotWidth::U40 => 5,
+            SlotWidth::U48 => 6,
+            SlotWidth::U64 => 8,
+        }
+    }
+
+    /// Largest offset a slot of this width can hold.
+    pub const fn max_offset(self) -> u64 {
+        u64::MAX >> (64 - 8 * self.bytes())
+    }
+
+    /// The narrowest width able to address every offset of a buffer `len` bytes long.
+    pub fn narrowest_for(len: u64) -> SlotWidth {
+        Self::ALL
+            .into_iter()
+            .find(|width| len.saturating_sub(1) <= width.max_offset())
+            .unwrap_or(SlotWidth::U64)
+    }
+
+    fn from_bytes(bytes: u8) -> Result<Self, OutaBounds> {
+        Self::ALL
+            .into_iter()
+            .find(|width| width.bytes() == u64::from(bytes))
+            .ok_or(OutaBounds)
+    }
+}
+
+/// Compression codec values in a quack may be stored with, see [Compression]
+/// for producing compressed quacks. Decoding compressed values needs the
+/// matching cargo feature, `lz4` or `zstd`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum Codec {
+    #[default]
+    None,
+    Lz4,
+    Zstd,
+}
+
+impl Codec {
+    fn tag(self) -> u8 {
+        match self {
+            Codec::None => 0,
+            Codec::Lz4 => 1,
+            Codec::Zstd => 2,
+        }
+    }
+
+    fn from_tag(tag: u8) -> Result<Self, OutaBounds> {
+        match tag {
+            0 => Ok(Codec::None),
+            1 => Ok(Codec::Lz4),
+            2 => Ok(Codec::Zstd),
+            _ => Err(OutaBounds),
+        }
+    }
+}
+
+/// Describes the on-disk layout of a quack.
+///
+/// [Format::LEGACY] is the original header-less layout, written by
+/// [Quack::initialize_assume_zeroed]. Any other format is recorded in a
+/// versioned header so readers pick it up automatically.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Format {
+    versioned: bool,
+    elements: ElementEncoding,
+    slot_width: SlotWidth,
+    sparse: bool,
+    shared: bool,
+    concurrent: bool,
+    codec: Codec,
+}
+
+impl Default for Format {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Format {
+    /// The original layout: no header, wide elements.
+    pub const LEGACY: Format = Format {
+        versioned: false,
+        elements: ElementEncoding::Wide,
+        slot_width: SlotWidth::U64,
+        sparse: false,
+        shared: false,
+        concurrent: false,
+        codec: Codec::None,
+    };
+
+    /// Versioned header with wide elements and 8 byte slots.
+    pub const fn new() -> Self {
+        Format {
+            versioned: true,
+            elements: ElementEncoding::Wide,
+            slot_width: SlotWidth::U64,
+            sparse: false,
+            shared: false,
+            concurrent: false,
+            codec: Codec::None,
+        }
+    }
+
+    /// Use the given element encoding. The result is always versioned.
+    pub const fn with_elements(mut self, elements: ElementEncoding) -> Self {
+        self.versioned = true;
+        self.elements = elements;
+        self
+    }
+
+    /// Use the given slot width. The result is always versioned.
+    pub const fn with_slot_width(mut self, slot_width: SlotWidth) -> Self {
+        self.versioned = true;
+        self.slot_width = slot_width;
+        self
+    }
+
+    /// Only store offsets for occupied slots, see [QuackBuilder::sparse_slots].
+    /// The result is always versioned.
+    pub const fn with_sparse_slots(mut self, sparse: bool) -> Self {
+        self.versioned = true;
+        self.sparse = sparse;
+        self
+    }
+
+    /// Allow elements to refer to another element's payload rather than
+    /// holding a copy, see [QuackBuilder::dedup]. Costs a bit of each element's length.
+    /// The result is always versioned.
+    pub const fn with_shared_payloads(mut self, shared: bool) -> Self {
+        self.versioned = true;
+        self.shared = shared;
+        self
+    }
+
+    /// Let readers in other threads or processes look at the quack while it is
+    /// being written to. Slots and store_len become aligned native-endian words,
+    /// published with release stores and read with acquire loads, so a reader
+    /// sees either the old or the new head of a list and never a torn pointer.
+    ///
+    /// Requires 8 byte slots, and a buffer aligned to 8 bytes, as mmaps are.
+    /// Backings implementing [AtomicWords] can also take writes from several
+    /// threads at once, see [Quack::write_shared] and [Quack::read_shared].
+    /// Quacks written this way can only be read on machines of the same byte order.
+    /// The result is always versioned.
+    pub const fn with_concurrent_access(mut self, concurrent: bool) -> Self {
+        self.versioned = true;
+        self.concurrent = concurrent;
+        self
+    }
+
+    /// Allow values to be stored compressed with the given codec. Each element
+    /// spends a bit of its length on whether it is compressed, so values written
+    /// with [Quack::write] are simply stored uncompressed.
+    /// The result is always versioned.
+    pub const fn with_codec(mut self, codec: Codec) -> Self {
+        self.versioned = true;
+        self.codec = codec;
+        self
+    }
+
+    pub const fn elements(&self) -> ElementEncoding {
+        self.elements
+    }
+
+    pub const fn slot_width(&self) -> SlotWidth {
+        self.slot_width
+    }
+
+    pub const fn sparse_slots(&self) -> bool {
+        self.sparse
+    }
+
+    pub const fn shared_payloads(&self) -> bool {
+        self.shared
+    }
+
+    pub const fn concurrent_access(&self) -> bool {
+        self.concurrent
+    }
+
+    pub const fn codec(&self) -> Codec {
+        self.codec
+    }
+
+    const fn flags(&self) -> u8 {
+        let mut flags = 0;
+        if self.sparse {
+            flags |= stor::FLAG_SPARSE;
+        }
+        if self.shared {
+            flags |= stor::FLAG_SHARED;
+        }
+        if self.concurrent {
+            flags |= stor::FLAG_CONCURRENT;
+            if cfg!(target_endian = "big") {
+                flags |= stor::FLAG_BIG_ENDIAN_WORDS;
+            }
+        }
+        flags
+    }
+
+    /// Whether this format starts with a versioned header.
+    pub const fn is_versioned(&self) -> bool {
+        self.versioned
+    }
+
+    /// Size of everything in front of the slots array.
+    pub const fn header_len(&self) -> u64 {
+        if self.versioned {
+            stor::HEADER_LEN
+        } else {
+            stor::LEGACY_SLOTS_START
+        }
+    }
+
+    /// Bytes of store consumed by writing a value of the given length.
+    pub fn element_size(&self, value_len: u64) -> Result<u64, OutaBounds> {
+        val::overhead(*self, value_len)?
+            .checked_add(value_len)
+            .ok_or(OutaBounds)
+    }
+
+    /// Calculate the required buffer size for a quack in this format
+    /// given a number of slots and the sizes of the values to be written.
+    ///
+    /// Sparse formats are sized as if every slot were occupied, the builder
+    /// knows better, see [QuackBuilder::size].
+    pub fn store_size<T>(&self, slot_count: u64, value_sizes: T) -> Result<u64, OutaBounds>
+    where
+        T: IntoIterator<Item = u64>,
+    {
+        let mut layout = stor::Layout::new(*self, slot_count);
+        layout.occupied_slots = slot_count;
+        layout.store_size(value_sizes)
+    }
+
+    /// Initializes a Quack in this format with a given number of slots
+    /// the data store provided must be all zeroes.
+    ///
+    /// Only the header and slots array need to be zero, leftover bytes there
+    /// would become pointers to garbage. Buffers that are known to start out
+    /// zeroed can skip the cost of [Format::initialize]: anonymous mmaps, and
+    /// files newly created or extended with `set_len`, read as zeroes on
+    /// every platform memmap2 supports, as does `vec![0; n]`.
+    ///
+    /// Sparse quacks can only be produced by [QuackBuilder].
+    pub fn initialize_assume_zeroed<B: AsMut<[u8]>>(
+        &self,
+        mut data: B,
+        num_slots: u64,
+    ) -> Result<Quack<B>, OutaBounds> {
+        if self.sparse || (self.concurrent && self.slot_width != SlotWidth::U64) {
+            return Err(OutaBounds);
+        }
+        let layout = stor::Layout::new(*self, num_slots);
+        let dat = data.as_mut();
+        if dat.len() < layout.store_start()? as usize {
+            return Err(OutaBounds);
+        }
+        layout.write(dat)?;
+        Ok(Quack::new(data))
+    }
+
+    /// Like [Format::initialize_assume_zeroed], but zeroes the header and
+    /// slots array first, so `data` may hold anything. The store is left
+    /// as is, it is overwritten as values are written.
+    pub fn initialize<B: AsMut<[u8]>>(
+        &self,
+        mut data: B,
+        num_slots: u64,
+    ) -> Result<Quack<B>, OutaBounds> {
+        let end = stor::Layout::new(*self, num_slots).store_start()?;
+        let end = usize::try_from(end).map_err(|_| OutaBounds)?;
+        data.as_mut().get_mut(..end).ok_or(OutaBounds)?.fill(0);
+        self.initialize_assume_zeroed(data, num_slots)
+    }
+
+    /// Like [Format::initialize_assume_zeroed], but fails if the header and
+    /// slots array aren't all zeroes rather than trusting they are.
+    pub fn initialize_checked<B: AsMut<[u8]>>(
+        &self,
+        mut data: B,
+        num_slots: u64,
+    ) -> Result<Quack<B>, OutaBounds> {
+        let end = stor::Layout::new(*self, num_slots).store_start()?;
+        let end = usize::try_from(end).map_err(|_| OutaBounds)?;
+        let region = data.as_mut().get(..end).ok_or(OutaBounds)?;
+        if region.iter().any(|&byte| byte != 0) {
+            return Err(OutaBounds);
+        }
+        self.initialize_assume_zeroed(data, num_slots)
     }
 }
 
 /// Calculate the required buffer size for the backing store
 /// given a number of slots and the sizes of the values to be written.
-/// Assumes each value will be written with 16 bytes of overhead.
+/// Assumes [Format::LEGACY], where each value is written with 16 bytes of overhead.
 pub fn calculate_store_size<T>(slot_count: u64, value_sizes: T) -> Result<u64, OutaBounds>
 where
     T: IntoIterator<Item = u64>,
 {
-    value_sizes
-        .into_iter()
-        .try_fold(stor::store_start(slot_count)?, |acc, size| {
-            // Each value has 16 bytes overhead (next pointer + len)
-            acc.checked_add(val::PAYLOAD_START)
-                .ok_or(OutaBounds)?
-                .checked_add(size)
-                .ok_or(OutaBounds)
-        })
+    Format::LEGACY.store_size(slot_count, value_sizes)
 }
 
 #[derive(Debug)]
@@ -133,11 +1100,19 @@ impl core::error::Error for OutaBounds {}
 pub struct Quack<B> {
     /// Single buffer holding num_slots, store_len, the slots array, and store data.
     data: B,
+    /// The header, parsed on first use. Nothing rewrites it once it's written.
+    layout: OnceLock<stor::Layout>,
+    /// The compression dictionary, prepared on first use and shared by reads.
+    dictionary: compress::Dictionary,
 }
 
 impl<B> Quack<B> {
     pub fn new(data: B) -> Self {
-        Quack { data }
+        Quack {
+            data,
+            layout: OnceLock::new(),
+            dictionary: compress::Dictionary::new(),
+        }
     }
 
     pub fn into_inner(self) -> B {
@@ -150,119 +1125,492 @@ impl<B> Quack<B> {
 }
 
 impl<B: AsRef<[u8]>> Quack<B> {
+    /// Reads the list for a given key, newest first, as it is now. Another
+    /// process committing a [Transaction] may be seen halfway, read through a
+    /// [Quack::snapshot] to see all of it or none.
     pub fn read(&self, k: u64) -> Result<Sequence<'_>, OutaBounds> {
+        let snapshot = QuackSnapshot {
+            data: self.data.as_ref(),
+            layout: self.layout()?,
+            watermark: u64::MAX,
+            dictionary: &self.dictionary,
+        };
+        snapshot.read(k)
+    }
+
+    /// A point-in-time view of the quack. Reads through the snapshot skip
+    /// anything written after it was taken, so they are repeatable while a
+    /// writer keeps appending, see [Format::with_concurrent_access].
+    ///
+    /// With several writers going through [Quack::write_shared] this only
+    /// holds once they stop: each reserves its space in the store before
+    /// linking it in, and a write that had reserved but not linked when the
+    /// snapshot was taken shows up in the snapshot's reads once it links.
+    pub fn snapshot(&self) -> Result<QuackSnapshot<'_>, OutaBounds> {
         let data = self.data.as_ref();
+        let layout = self.layout()?;
+        let watermark = layout
+            .store_start()?
+            .checked_add(layout.read_store_len(data)?)
+            .ok_or(OutaBounds)?;
+        Ok(QuackSnapshot {
+            data,
+            layout,
+            watermark,
+            dictionary: &self.dictionary,
+        })
+    }
 
-        let num_slots = stor::read_num_slots(data)?;
+    pub fn slots(&self) -> Result<u64, OutaBounds> {
+        stor::read_num_slots(self.data.as_ref())
+    }
 
-        let Some(slot_index) = k.checked_rem(num_slots) else {
-            return Ok(Sequence::empty());
-        };
+    /// Every non-empty slot with its list, in slot order.
+    /// See [QuackSnapshot::iter_slots].
+    pub fn iter_slots(&self) -> Result<Slots<'_>, OutaBounds> {
+        Ok(self.snapshot()?.iter_slots())
+    }
 
-        let head = stor::read_slot(data, slot_index)?;
+    /// Every value, along with the index of its slot.
+    /// See [QuackSnapshot::iter_entries].
+    pub fn iter_entries(&self) -> Result<Entries<'_>, OutaBounds> {
+        Ok(self.snapshot()?.iter_entries())
+    }
 
-        Ok(Sequence { data, next: head })
+    /// Splits the slots into up to `n` balanced ranges.
+    /// See [QuackSnapshot::slot_ranges].
+    pub fn slot_ranges(
+        &self,
+        n: usize,
+        balance: Balance,
+    ) -> Result<Vec<core::ops::Range<u64>>, OutaBounds> {
+        self.snapshot()?.slot_ranges(n, balance)
     }
 
-    pub fn slots(&self) -> Result<u64, OutaBounds> {
-        stor::read_num_slots(self.data.as_ref())
+    /// Describes how full the quack is. See [QuackSnapshot::stats].
+    pub fn stats(&self) -> Result<QuackStats, OutaBounds> {
+        self.snapshot()?.stats()
+    }
+
+    /// Like [Quack::stats], only walking about `slots` slots.
+    /// See [QuackSnapshot::sampled_stats].
+    pub fn sampled_stats(&self, slots: u64) -> Result<QuackStats, OutaBounds> {
+        self.snapshot()?.sampled_stats(slots)
+    }
+
+    /// Every element, in the order they are stored in.
+    /// See [QuackSnapshot::scan_store].
+    pub fn scan_store(&self) -> Result<StoreScan<'_>, OutaBounds> {
+        self.snapshot()?.scan_store()
+    }
+
+    /// Checks every list and value for damage, returning the number of values.
+    /// See [QuackSnapshot::verify].
+    pub fn verify(&self) -> Result<u64, OutaBounds> {
+        self.snapshot()?.verify()
+    }
+
+    /// The format this quack was written in.
+    pub fn format(&self) -> Result<Format, OutaBounds> {
+        Ok(self.layout()?.format)
+    }
+
+    fn layout(&self) -> Result<stor::Layout, OutaBounds> {
+        if let Some(layout) = self.layout.get() {
+            return Ok(*layout);
+        }
+        let layout = stor::Layout::read(self.data.as_ref())?;
+        Ok(*self.layout.get_or_init(|| layout))
+    }
+
+    /// Bytes of store the buffer has room for, in use or not.
+    pub fn capacity(&self) -> Result<u64, OutaBounds> {
+        let data = self.data.as_ref();
+        let store_start = self.layout()?.store_start()?;
+        (data.len() as u64)
+            .checked_sub(store_start)
+            .ok_or(OutaBounds)
+    }
+
+    /// Bytes of store in use, store_len.
+    pub fn used(&self) -> Result<u64, OutaBounds> {
+        let data = self.data.as_ref();
+        self.layout()?.read_store_len(data)
+    }
+
+    /// Bytes of store left for more writes.
+    pub fn remaining(&self) -> Result<u64, OutaBounds> {
+        Ok(self.capacity()?.saturating_sub(self.used()?))
+    }
+
+    /// Whether [Quack::write] has room for a value `value_len` bytes long,
+    /// whichever slot it goes to. Sparse formats only have room in slots
+    /// occupied when built, and relative elements only link to elements
+    /// within 2 GiB, which depend on the slot, see [Quack::can_write].
+    pub fn can_fit(&self, value_len: u64) -> Result<bool, OutaBounds> {
+        self.fits(1, value_len)
+    }
+
+    /// Whether [Quack::write] can write a value `value_len` bytes long for
+    /// the key `k`. Like [Quack::can_fit], but also checks the key's slot.
+    pub fn can_write(&self, k: u64, value_len: u64) -> Result<bool, OutaBounds> {
+        if !self.fits(1, value_len)? {
+            return Ok(false);
+        }
+        let data = self.data.as_ref();
+        let layout = self.layout()?;
+        let slot_index = k.checked_rem(layout.num_slots).ok_or(OutaBounds)?;
+        if layout.slot_offset(data, slot_index)?.is_none() {
+            return Ok(false);
+        }
+        let head = layout.read_slot(data, slot_index)?;
+        let start = layout
+            .store_start()?
+            .checked_add(layout.read_store_len(data)?)
+            .ok_or(OutaBounds)?;
+        Ok(val::next_bytes(layout.format, start, head).is_ok())
+    }
+
+    /// Checks that `n_values` values, `total_bytes` long together, would fit,
+    /// failing with [OutaBounds] if they might not. Compact elements spend
+    /// more on longer values, so this assumes the worst about how the bytes
+    /// are split between values.
+    ///
+    /// Nothing is set aside, so this only holds until someone else writes.
+    pub fn try_reserve(&self, n_values: u64, total_bytes: u64) -> Result<(), OutaBounds> {
+        if self.fits(n_values, total_bytes)? {
+            Ok(())
+        } else {
+            Err(OutaBounds)
+        }
+    }
+
+    fn fits(&self, n_values: u64, total_bytes: u64) -> Result<bool, OutaBounds> {
+        let data = self.data.as_ref();
+        let layout = self.layout()?;
+        let store_start = layout.store_start()?;
+        let used = layout.read_store_len(data)?;
+        let needed = val::overhead(layout.format, total_bytes)?
+            .checked_mul(n_values)
+            .and_then(|overhead| overhead.checked_add(total_bytes));
+        let Some(end) = needed
+            .and_then(|needed| needed.checked_add(used))
+            .and_then(|len| len.checked_add(store_start))
+        else {
+            return Ok(false);
+        };
+        // Compact elements link to earlier ones by u32 offsets, so writes fail
+        // once any slot leads past that. The last value written starts before
+        // the end, so this is conservative.
+        let max_offset = match layout.format.elements() {
+            ElementEncoding::Compact => u32::MAX.into(),
+            _ => u64::MAX,
+        };
+        let addressable = end <= layout.format.slot_width.max_offset().min(max_offset);
+        Ok(n_values == 0 || (end <= data.len() as u64 && addressable))
     }
 }
 
 impl<B: AsMut<[u8]>> Quack<B> {
     /// Initializes the Quack with a given number of slots
     /// the data store provided must be all zeroes.
-    pub fn initialize_assume_zeroed(mut data: B, num_slots: u64) -> Result<Self, OutaBounds> {
+    ///
+    /// Uses [Format::LEGACY], see [Format::initialize_assume_zeroed] for other formats.
+    pub fn initialize_assume_zeroed(data: B, num_slots: u64) -> Result<Self, OutaBounds> {
+        Format::LEGACY.initialize_assume_zeroed(data, num_slots)
+    }
+
+    /// Initializes the Quack with a given number of slots, zeroing what needs
+    /// to be first. See [Format::initialize].
+    pub fn initialize(data: B, num_slots: u64) -> Result<Self, OutaBounds> {
+        Format::LEGACY.initialize(data, num_slots)
+    }
+
+    /// Initializes the Quack with a given number of slots, failing if the
+    /// data store isn't zeroed where it needs to be.
+    /// See [Format::initialize_checked].
+    pub fn initialize_checked(data: B, num_slots: u64) -> Result<Self, OutaBounds> {
+        Format::LEGACY.initialize_checked(data, num_slots)
+    }
+
+    /// Opens an existing quack for further writes, checking first that its
+    /// header is one we understand, that its store fits in `data`, and that
+    /// every slot points into the store, so appends land inside the buffer
+    /// without overwriting anything. [Quack::new] trusts the buffer instead.
+    ///
+    /// A crash midway through a write can leave a slot pointing past
+    /// store_len, which fails here, see [Quack::open_mut_and_recover] for
+    /// files that may not have been closed cleanly.
+    pub fn open_mut(data: B) -> Result<Self, OutaBounds> {
+        let (mut quack, watermark) = Self::open_store(data)?;
+        quack.check_slot_heads(watermark)?;
+        Ok(quack)
+    }
+
+    /// Like [Quack::open_mut], but first looks for slots pointing at elements
+    /// written past store_len by writes or commits that didn't finish, and
+    /// rolls them back with [Quack::recover]. Returns the quack and the
+    /// number of slots repaired.
+    pub fn open_mut_and_recover(data: B) -> Result<(Self, u64), OutaBounds> {
+        let (mut quack, watermark) = Self::open_store(data)?;
+        let repaired = quack.recover()?;
+        quack.check_slot_heads(watermark)?;
+        Ok((quack, repaired))
+    }
+
+    /// Checks the header and that the store fits in `data`. Returns the quack
+    /// and where its store ends.
+    fn open_store(mut data: B) -> Result<(Self, u64), OutaBounds> {
         let dat = data.as_mut();
-        if dat.len() < stor::store_start(num_slots)? as usize {
+        let layout = stor::Layout::read(dat)?;
+        if layout.format.concurrent_access() {
+            check_aligned(dat.as_ptr())?;
+        }
+        let end = layout
+            .store_start()?
+            .checked_add(layout.read_store_len(dat)?)
+            .ok_or(OutaBounds)?;
+        if end > dat.len() as u64 {
             return Err(OutaBounds);
         }
-        stor::write_store_len(dat, 0)?;
-        stor::write_num_slots(dat, num_slots)?;
-        Ok(Quack { data })
+        Ok((Quack::new(data), end))
+    }
+
+    fn check_slot_heads(&mut self, watermark: u64) -> Result<(), OutaBounds> {
+        let data = self.data.as_mut();
+        let layout = stor::Layout::read(data)?;
+        verify::check_slot_heads(data, &layout, watermark)
     }
 
     /// Writes an item for a given key by prepending it to the linked list in that slot.
     pub fn write(&mut self, k: u64, v: &[u8]) -> Result<(), OutaBounds> {
         let data = self.data.as_mut();
 
-        let num_slots = stor::read_num_slots(data)?;
-        let store_len = stor::read_store_len(data)?;
+        let layout = stor::Layout::read(data)?;
+        let store_len = layout.read_store_len(data)?;
 
-        let slot_index = k.checked_rem(num_slots).ok_or(OutaBounds)?;
+        let slot_index = k.checked_rem(layout.num_slots).ok_or(OutaBounds)?;
 
-        let new_len = (size_of::<u64>() as u64 * 2)
-            .checked_add(v.len() as u64)
-            .ok_or(OutaBounds)?
-            .checked_add(store_len)
-            .ok_or(OutaBounds)?;
+        let old_head = layout.read_slot(data, slot_index)?;
+        let (new_head, new_len) = append(data, &layout, store_len, old_head, v)?;
+        layout.write_slot(data, slot_index, new_head)?;
+        layout.write_store_len(data, new_len)?;
 
-        let store_start = stor::store_start(num_slots)?;
+        Ok(())
+    }
 
-        let required_data_size = store_start.checked_add(new_len).ok_or(OutaBounds)?;
+    /// Starts a batch of writes that become visible together on
+    /// [Transaction::commit], or not at all.
+    pub fn transaction(&mut self) -> Result<Transaction<'_>, OutaBounds> {
+        Transaction::new(self.data.as_mut())
+    }
 
-        if required_data_size > data.len() as u64 {
-            return Err(OutaBounds);
+    /// Repairs a quack after a crash mid-commit, by rolling back slots that
+    /// point past store_len to their last committed element. Returns the number
+    /// of slots repaired. Whatever lies past store_len is garbage and will be
+    /// overwritten by later writes. Fails if the elements past store_len link
+    /// in a loop, which no crash leaves behind.
+    pub fn recover(&mut self) -> Result<u64, OutaBounds> {
+        let data = self.data.as_mut();
+        let layout = stor::Layout::read(data)?;
+        let watermark = layout
+            .store_start()?
+            .checked_add(layout.read_store_len(data)?)
+            .ok_or(OutaBounds)?;
+        let mut repaired = 0;
+        for slot_index in 0..layout.num_slots {
+            let mut head = layout.read_slot(data, slot_index)?;
+            if head < watermark {
+                continue;
+            }
+            // see Sequence for why this ends
+            let mut appends =
+                (data.len() as u64).saturating_sub(watermark) / layout.format.element_size(0)?;
+            while head >= watermark {
+                appends = appends.checked_sub(1).ok_or(OutaBounds)?;
+                head = val::read(data, layout.format, head)?.next;
+            }
+            layout.write_slot(data, slot_index, head)?;
+            repaired += 1;
         }
+        Ok(repaired)
+    }
+}
 
-        let old_head = stor::read_slot(data, slot_index)?;
-        let new_head = store_len.checked_add(store_start).ok_or(OutaBounds)?;
-        val::write(data, new_head, old_head, v)?;
-        stor::write_slot(data, slot_index, new_head)?;
-        stor::write_store_len(data, new_len)?;
+/// Writes an element for `v` at the end of the store, pointing at `next`,
+/// without publishing it. Returns its offset and the store_len including it.
+fn append(
+    data: &mut [u8],
+    layout: &stor::Layout,
+    store_len: u64,
+    next: u64,
+    v: &[u8],
+) -> Result<(u64, u64), OutaBounds> {
+    let new_len = layout
+        .format
+        .element_size(v.len() as u64)?
+        .checked_add(store_len)
+        .ok_or(OutaBounds)?;
 
-        Ok(())
+    let store_start = layout.store_start()?;
+
+    let required_data_size = store_start.checked_add(new_len).ok_or(OutaBounds)?;
+
+    if required_data_size > data.len() as u64 {
+        return Err(OutaBounds);
+    }
+
+    let new_head = store_len.checked_add(store_start).ok_or(OutaBounds)?;
+    if new_head > layout.format.slot_width.max_offset() {
+        return Err(OutaBounds);
+    }
+    val::write(data, layout.format, new_head, next, v, val::Tags::default())?;
+    Ok((new_head, new_len))
+}
+
+/// A view of a quack pinned to the store_len it had when [Quack::snapshot] was
+/// called. Writes only ever prepend elements at the end of the store, so
+/// ignoring elements past that point hides exactly the later writes, except
+/// for those [Quack::write_shared] had reserved space for but not yet linked.
+#[derive(Clone, Copy)]
+pub struct QuackSnapshot<'a> {
+    data: &'a [u8],
+    layout: stor::Layout,
+    /// Elements at or past this offset were written after the snapshot.
+    watermark: u64,
+    dictionary: &'a compress::Dictionary,
+}
+
+impl<'a> QuackSnapshot<'a> {
+    pub fn read(&self, k: u64) -> Result<Sequence<'a>, OutaBounds> {
+        let Some(slot_index) = k.checked_rem(self.layout.num_slots) else {
+            return Ok(Sequence::empty());
+        };
+        self.slot(slot_index)
+    }
+
+    /// The list in the slot at `slot_index`, which must be less than num_slots.
+    fn slot(&self, slot_index: u64) -> Result<Sequence<'a>, OutaBounds> {
+        let head = self.layout.read_slot(self.data, slot_index)?;
+
+        Ok(Sequence {
+            data: self.data,
+            next: head,
+            layout: self.layout,
+            watermark: self.watermark,
+            decompressor: compress::Decompressor::new(self.dictionary),
+        })
+    }
+
+    /// The store_len the snapshot was taken at.
+    pub fn store_len(&self) -> Result<u64, OutaBounds> {
+        self.watermark
+            .checked_sub(self.layout.store_start()?)
+            .ok_or(OutaBounds)
     }
 }
 
 /// An iterator over values stored in the Quack.
 /// Essentially a view of a linked list.
+///
+/// Iterating yields values as written, decompressing those stored compressed,
+/// see [Sequence::try_next_decoded]. [Sequence::try_next] returns every value
+/// as stored.
+#[derive(Clone)]
 pub struct Sequence<'a> {
     data: &'a [u8],
     next: u64,
+    layout: stor::Layout,
+    /// Elements at or past this offset are skipped, see [QuackSnapshot].
+    watermark: u64,
+    decompressor: compress::Decompressor<'a>,
 }
 
 impl<'a> Iterator for Sequence<'a> {
-    type Item = &'a [u8];
+    type Item = Cow<'a, [u8]>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        self.try_next().ok().unwrap_or(None)
+        self.try_next_decoded().ok().unwrap_or(None)
     }
 }
 
 impl<'a> Sequence<'a> {
     fn empty() -> Self {
-        Sequence { data: &[], next: 0 }
+        Sequence {
+            data: &[],
+            next: 0,
+            layout: stor::Layout::new(Format::LEGACY, 0),
+            watermark: u64::MAX,
+            decompressor: compress::Decompressor::new(&compress::NO_DICTIONARY),
+        }
     }
 
     /// Return the next element in this linked list (if any),
     /// or an error if the data is out of bounds or corrupt.
     pub fn try_next(&mut self) -> Result<Option<&'a [u8]>, OutaBounds> {
+        Ok(self.try_next_element()?.map(|element| element.payload))
+    }
+
+    /// Like [Sequence::try_next], but decompresses values that were stored
+    /// compressed. Fails if the codec they were compressed with isn't enabled.
+    pub fn try_next_decoded(&mut self) -> Result<Option<Cow<'a, [u8]>>, OutaBounds> {
+        let Some(element) = self.try_next_element()? else {
+            return Ok(None);
+        };
+        if !element.tags.compressed {
+            return Ok(Some(Cow::Borrowed(element.payload)));
+        }
+        let mut buf = Vec::new();
+        self.decompress_into(element.payload, &mut buf)?;
+        Ok(Some(Cow::Owned(buf)))
+    }
+
+    /// Like [Sequence::try_next_decoded], but writes the value into `buf`,
+    /// replacing its contents. Returns false once the list is exhausted.
+    pub fn try_next_into(&mut self, buf: &mut Vec<u8>) -> Result<bool, OutaBounds> {
+        let Some(element) = self.try_next_element()? else {
+            return Ok(false);
+        };
+        if element.tags.compressed {
+            self.decompress_into(element.payload, buf)?;
+        } else {
+            buf.clear();
+            buf.extend_from_slice(element.payload);
+        }
+        Ok(true)
+    }
+
+    /// Returns the next element, with shared payloads resolved.
+    fn try_next_element(&mut self) -> Result<Option<val::Element<'a>>, OutaBounds> {
+        if self.next >= self.watermark {
+            // Concurrent writers may link appends in any order, but there can't
+            // be more of them than fit past the watermark, else the list loops.
+            let mut appends = (self.data.len() as u64).saturating_sub(self.watermark)
+                / self.layout.format.element_size(0)?;
+            while self.next >= self.watermark {
+                appends = appends.checked_sub(1).ok_or(OutaBounds)?;
+                self.next = val::read(self.data, self.layout.format, self.next)?.next;
+            }
+        }
         if self.next == 0 {
             return Ok(None);
         }
-        let next_start = read_u64(
-            self.data,
-            val::NEXT_POINTER_OFFSET
-                .checked_add(self.next)
-                .ok_or(OutaBounds)?,
-        )?;
-        let payload_len = read_u64(
-            self.data,
-            val::PAYLOAD_LEN_OFFSET
-                .checked_add(self.next)
-                .ok_or(OutaBounds)?,
-        )?;
-        let ret = get_range_dynamic(
-            self.data,
-            val::PAYLOAD_START
-                .checked_add(self.next)
-                .ok_or(OutaBounds)?,
-            payload_len,
-        )?;
-        self.next = next_start;
-        Ok(Some(ret))
+        let element = val::resolve(self.data, self.layout.format, self.next)?;
+        self.next = element.next;
+        Ok(Some(element))
     }
+
+    fn decompress_into(&mut self, stored: &[u8], buf: &mut Vec<u8>) -> Result<(), OutaBounds> {
+        let (data, layout) = (self.data, self.layout);
+        let dictionary = || layout.dictionary(data).map(Cow::Borrowed);
+        self.decompressor
+            .decompress_into(layout.format.codec(), dictionary, stored, buf)
+    }
+}
+
+/// Reports a damaged quack read through an `io` interface.
+fn invalid_data(e: OutaBounds) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, e)
 }
 
 fn get_range<const N: usize>(data: &[u8], start: u64) -> Result<&[u8; N], OutaBounds> {
@@ -279,11 +1627,68 @@ fn get_range_dynamic(data: &[u8], start: u64, len: u64) -> Result<&[u8], OutaBou
     data.get(start..end).ok_or(OutaBounds)
 }
 
+fn read_u8(data: &[u8], start: u64) -> Result<u8, OutaBounds> {
+    let raw = get_range::<1>(data, start)?;
+    Ok(raw[0])
+}
+
+fn write_u8(data: &mut [u8], start: u64, value: u8) -> Result<(), OutaBounds> {
+    write_range(data, start, &[value])
+}
+
 fn read_u64(data: &[u8], start: u64) -> Result<u64, OutaBounds> {
     let raw = get_range::<8>(data, start)?;
     Ok(u64::from_be_bytes(*raw))
 }
 
+/// Reads a big-endian unsigned integer `width` bytes wide, `width` must be at most 8.
+fn read_uint(data: &[u8], start: u64, width: u64) -> Result<u64, OutaBounds> {
+    let raw = get_range_dynamic(data, start, width)?;
+    let mut bytes = [0u8; 8];
+    bytes[8 - raw.len()..].copy_from_slice(raw);
+    Ok(u64::from_be_bytes(bytes))
+}
+
+/// Writes a big-endian unsigned integer `width` bytes wide, `width` must be at most 8.
+fn write_uint(data: &mut [u8], start: u64, width: u64, value: u64) -> Result<(), OutaBounds> {
+    let bytes = value.to_be_bytes();
+    let (high, low) = bytes.split_at(8 - width as usize);
+    if high.iter().any(|b| *b != 0) {
+        return Err(OutaBounds);
+    }
+    write_range(data, start, low)
+}
+
+/// Checks that an 8 byte word can be accessed atomically through `ptr`.
+fn check_aligned(ptr: *const u8) -> Result<(), OutaBounds> {
+    if ptr.align_offset(align_of::<AtomicU64>()) != 0 {
+        return Err(OutaBounds);
+    }
+    Ok(())
+}
+
+fn load_acquire(data: &[u8], start: u64) -> Result<u64, OutaBounds> {
+    let word = get_range::<8>(data, start)?;
+    check_aligned(word.as_ptr())?;
+    // SAFETY: the word is in bounds and aligned, checked above, and only read.
+    // Nothing in this process writes to it while `data` borrows it, backings
+    // shared between threads are read through [Quack::read_shared] instead.
+    // Writers in other processes only access it atomically, as we do here.
+    let atomic = unsafe { &*word.as_ptr().cast::<AtomicU64>() };
+    Ok(atomic.load(Ordering::Acquire))
+}
+
+fn store_release(data: &mut [u8], start: u64, value: u64) -> Result<(), OutaBounds> {
+    let start = start as usize;
+    let end = start.checked_add(8).ok_or(OutaBounds)?;
+    let word = data.get_mut(start..end).ok_or(OutaBounds)?;
+    check_aligned(word.as_ptr())?;
+    // SAFETY: as in [load_acquire], and we hold the only local reference to the word.
+    let atomic = unsafe { AtomicU64::from_ptr(word.as_mut_ptr() as *mut u64) };
+    atomic.store(value, Ordering::Release);
+    Ok(())
+}
+
 fn write_u64(data: &mut [u8], start: u64, value: u64) -> Result<(), OutaBounds> {
     let start = start as usize;
     let end = start.checked_add(8).ok_or(OutaBounds)?;
@@ -320,7 +1725,7 @@ mod tests {
         quack.write(0, b"world").unwrap();
 
         let items = quack.read(0).unwrap().collect::<Vec<_>>();
-        assert_eq!(&items[..], &[b"world", b"hello"]);
+        assert_eq!(&items[..], &[&b"world"[..], b"hello"]);
     }
 
     #[test]
@@ -334,9 +1739,9 @@ mod tests {
         quack.write(1, b"world").unwrap();
         quack.write(2, b"quack").unwrap();
 
-        assert_eq!(&quack.read(0).unwrap().collect::<Vec<_>>(), &[b"hello"]);
-        assert_eq!(&quack.read(1).unwrap().collect::<Vec<_>>(), &[b"world"]);
-        assert_eq!(&quack.read(2).unwrap().collect::<Vec<_>>(), &[b"quack"]);
+        assert_eq!(quack.read(0).unwrap().collect::<Vec<_>>(), [&b"hello"[..]]);
+        assert_eq!(quack.read(1).unwrap().collect::<Vec<_>>(), [&b"world"[..]]);
+        assert_eq!(quack.read(2).unwrap().collect::<Vec<_>>(), [&b"quack"[..]]);
     }
 
     #[test]
@@ -352,4 +1757,342 @@ mod tests {
 
         assert!(quack.read(1).unwrap().next().is_none());
     }
+
+    #[test]
+    fn compact_elements() {
+        let format = Format::new().with_elements(ElementEncoding::Compact);
+        let values: [&[u8]; 3] = [b"hello", &[7; 200], b""];
+        let size = format
+            .store_size(4, values.iter().map(|v| v.len() as u64))
+            .unwrap();
+        assert_eq!(size, 64 + 4 * 8 + (5 + 5) + (6 + 200) + 5);
+
+        let mut quack = format
+            .initialize_assume_zeroed(vec![0u8; size as usize], 4)
+            .unwrap();
+        for v in values {
+            quack.write(1, v).unwrap();
+        }
+        assert!(quack.write(1, b"").is_err());
+
+        assert_eq!(quack.format().unwrap(), format);
+        let items = quack.read(1).unwrap().collect::<Vec<_>>();
+        assert_eq!(items, [&b""[..], &[7; 200][..], b"hello"]);
+    }
+
+    #[test]
+    fn relative_elements() {
+        let format = Format::new().with_elements(ElementEncoding::Relative);
+        let size = format.store_size(2, [5, 5, 5]).unwrap();
+        assert_eq!(
+            size,
+            Format::new()
+                .with_elements(ElementEncoding::Compact)
+                .store_size(2, [5, 5, 5])
+                .unwrap()
+        );
+        let mut quack = format
+            .initialize_assume_zeroed(vec![0u8; size as usize], 2)
+            .unwrap();
+        let mut builder = QuackBuilder::new(2).elements(ElementEncoding::Relative);
+        for (k, v) in [(1, b"hello"), (0, b"quack"), (1, b"world")] {
+            quack.write(k, v).unwrap();
+            builder.insert(k, v).unwrap();
+        }
+        let built = builder.build().unwrap();
+        assert_eq!(
+            built.format().unwrap().elements(),
+            ElementEncoding::Relative
+        );
+        for quack in [&quack, &built] {
+            let items = quack.read(1).unwrap().collect::<Vec<_>>();
+            assert_eq!(items, [&b"world"[..], b"hello"]);
+        }
+        // the newest element links back past the one in slot 0, 20 bytes in all
+        let head = stor::Layout::read(quack.ref_inner())
+            .and_then(|layout| layout.read_slot(quack.ref_inner(), 1))
+            .unwrap();
+        let distance = get_range::<4>(quack.ref_inner(), head).unwrap();
+        assert_eq!(i32::from_be_bytes(*distance), -20);
+    }
+
+    #[test]
+    fn codec_format_stores_writes_uncompressed() {
+        let format = Format::new()
+            .with_elements(ElementEncoding::Compact)
+            .with_codec(Codec::Zstd);
+        let size = format.store_size(2, [127]).unwrap();
+        // 127 no longer fits a single byte once shifted to make room for the flag
+        assert_eq!(size, 64 + 2 * 8 + 4 + 2 + 127);
+
+        let mut quack = format
+            .initialize_assume_zeroed(vec![0u8; size as usize], 2)
+            .unwrap();
+        quack.write(1, &[9; 127]).unwrap();
+        assert_eq!(quack.format().unwrap().codec(), Codec::Zstd);
+        let decoded = quack.read(1).unwrap().collect::<Vec<_>>();
+        assert_eq!(decoded, [&[9; 127][..]]);
+    }
+
+    #[test]
+    fn concurrent_reader_sees_whole_lists() {
+        let format = Format::new().with_concurrent_access(true);
+        let writes = 2000u64;
+        let size = format.store_size(4, (0..writes).map(|_| 8)).unwrap();
+        let file = tempfile::tempfile().unwrap();
+        file.set_len(size).unwrap();
+
+        // Two maps of the same file, as a writer and a reader process would have.
+        let writer = unsafe { memmap2::MmapMut::map_mut(&file).unwrap() };
+        let reader = unsafe { memmap2::Mmap::map(&file).unwrap() };
+        let mut quack = format.initialize_assume_zeroed(writer, 4).unwrap();
+        let reader = Quack::new(reader);
+
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                for i in 0..writes {
+                    quack.write(i % 4, &i.to_be_bytes()).unwrap();
+                }
+            });
+            s.spawn(|| {
+                for _ in 0..writes {
+                    for k in 0..4 {
+                        let items = reader
+                            .read(k)
+                            .unwrap()
+                            .map(|v| u64::from_be_bytes(v[..].try_into().unwrap()))
+                            .collect::<Vec<_>>();
+                        // newest first, and nothing missing behind the head
+                        let expected = items.first().map_or(0, |head| head / 4 + 1);
+                        assert_eq!(items.len() as u64, expected);
+                        assert!(
+                            items
+                                .iter()
+                                .rev()
+                                .copied()
+                                .eq((k..).step_by(4).take(items.len()))
+                        );
+                    }
+                }
+            });
+        });
+        assert_eq!(reader.read(3).unwrap().count(), 500);
+    }
+
+    #[test]
+    fn snapshot_ignores_later_writes() {
+        let format = Format::new().with_concurrent_access(true);
+        let size = format.store_size(2, [5; 4]).unwrap();
+        let quack = format
+            .initialize_assume_zeroed(AtomicBuffer::zeroed(size as usize), 2)
+            .unwrap();
+        quack.write_shared(0, b"hello").unwrap();
+        quack.write_shared(1, b"quack").unwrap();
+
+        let snapshot = quack.snapshot_shared().unwrap();
+        quack.write_shared(0, b"world").unwrap();
+        quack.write_shared(1, b"again").unwrap();
+
+        assert_eq!(snapshot.store_len().unwrap(), 2 * (16 + 5));
+        assert_eq!(
+            snapshot.read(0).unwrap().collect::<Vec<_>>(),
+            [&b"hello"[..]]
+        );
+        assert_eq!(
+            snapshot.read(1).unwrap().collect::<Vec<_>>(),
+            [&b"quack"[..]]
+        );
+        assert_eq!(
+            quack.read_shared(0).unwrap().collect::<Vec<_>>(),
+            [b"world", b"hello"]
+        );
+        assert_eq!(
+            quack.read_shared(1).unwrap().collect::<Vec<_>>(),
+            [b"again", b"quack"]
+        );
+    }
+
+    #[test]
+    fn snapshot_skips_damaged_appends() {
+        let mut quack = Quack::initialize_assume_zeroed(vec![0u8; 256], 4).unwrap();
+        quack.write(1, b"hello").unwrap();
+        let store_len = quack.used().unwrap();
+        quack.write(1, b"torn").unwrap();
+        // an element past store_len linking to itself
+        let mut data = quack.into_inner();
+        stor::write_store_len(&mut data, store_len).unwrap();
+        write_u64(&mut data, 69, 69).unwrap();
+        assert!(
+            Quack::new(data)
+                .snapshot()
+                .unwrap()
+                .read(1)
+                .unwrap()
+                .try_next()
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn snapshot_skips_appends_in_any_order() {
+        let format = Format::new().with_concurrent_access(true);
+        let mut quack = format.initialize_assume_zeroed(vec![0u8; 256], 2).unwrap();
+        quack.write(0, b"hello").unwrap();
+        let store_len = quack.used().unwrap();
+        quack.write(0, b"lower").unwrap();
+        let lower = quack.used().unwrap() - (16 + 5);
+        quack.write(0, b"upper").unwrap();
+        let upper = quack.used().unwrap() - (16 + 5);
+
+        // as two writers leave it when the one reserving first links in last
+        let mut data = quack.into_inner();
+        let layout = stor::Layout::read(&data).unwrap();
+        let (lower, upper) = (
+            layout.store_start().unwrap() + lower,
+            layout.store_start().unwrap() + upper,
+        );
+        let hello = val::read(&data, format, lower).unwrap().next;
+        val::write_next(&mut data, format, lower, upper).unwrap();
+        val::write_next(&mut data, format, upper, hello).unwrap();
+        layout.write_slot(&mut data, 0, lower).unwrap();
+        layout.write_store_len(&mut data, store_len).unwrap();
+        let mut quack = Quack::new(data);
+        let snapshot = quack.snapshot().unwrap();
+        assert_eq!(
+            snapshot.read(0).unwrap().collect::<Vec<_>>(),
+            [&b"hello"[..]]
+        );
+        assert_eq!(quack.recover().unwrap(), 1);
+    }
+
+    #[test]
+    fn legacy_store_size() {
+        let size = calculate_store_size(4, [5, 5]).unwrap();
+        let mut quack = Quack::initialize_assume_zeroed(vec![0u8; size as usize], 4).unwrap();
+        quack.write(0, b"hello").unwrap();
+        quack.write(3, b"world").unwrap();
+        assert!(quack.write(3, b"").is_err());
+        assert_eq!(quack.format().unwrap(), Format::LEGACY);
+    }
+
+    #[test]
+    fn initialize_dirty_buffer() {
+        let dirty = vec![0xffu8; 256];
+        assert!(Quack::initialize_checked(dirty.clone(), 4).is_err());
+        let mut quack = Quack::initialize(dirty, 4).unwrap();
+        for k in 0..4 {
+            assert!(quack.read(k).unwrap().next().is_none());
+        }
+        quack.write(1, b"hello").unwrap();
+        assert_eq!(quack.read(1).unwrap().collect::<Vec<_>>(), [&b"hello"[..]]);
+
+        // only the header and slots need to be zero
+        let mut data = vec![0u8; 256];
+        data[200..].fill(0xff);
+        assert!(Format::new().initialize_checked(data, 4).is_ok());
+        assert!(Quack::initialize(vec![0u8; 16], 4).is_err());
+    }
+
+    #[test]
+    fn reopen_after_crash() {
+        let mut quack = Quack::initialize_assume_zeroed(vec![0u8; 256], 4).unwrap();
+        quack.write(1, b"hello").unwrap();
+        let store_len = quack.used().unwrap();
+        quack.write(1, b"torn").unwrap();
+        // as if we crashed after linking the element, before bumping store_len
+        let mut data = quack.into_inner();
+        stor::write_store_len(&mut data, store_len).unwrap();
+        // appends would overwrite the element slot 1 points at
+        assert!(Quack::open_mut(data.clone()).is_err());
+
+        let (mut quack, repaired) = Quack::open_mut_and_recover(data).unwrap();
+        assert_eq!(repaired, 1);
+        quack.write(1, b"world").unwrap();
+        assert_eq!(
+            quack.read(1).unwrap().collect::<Vec<_>>(),
+            [&b"world"[..], b"hello"]
+        );
+        assert!(Quack::open_mut(quack.into_inner()).is_ok());
+
+        let mut cycle = Quack::initialize_assume_zeroed(vec![0u8; 256], 4).unwrap();
+        cycle.write(1, b"hello").unwrap();
+        let store_len = cycle.used().unwrap();
+        cycle.write(1, b"torn").unwrap();
+        let mut data = cycle.into_inner();
+        stor::write_store_len(&mut data, store_len).unwrap();
+        write_u64(&mut data, 69, 69).unwrap();
+        assert!(Quack::open_mut_and_recover(data).is_err());
+
+        // a slot pointing into the slots themselves is damage no crash leaves
+        let mut misplaced = Quack::initialize_assume_zeroed(vec![0u8; 256], 4).unwrap();
+        misplaced.write(1, b"hello").unwrap();
+        let mut data = misplaced.into_inner();
+        write_u64(&mut data, 24, 16).unwrap();
+        assert!(Quack::open_mut(data.clone()).is_err());
+        assert!(Quack::open_mut_and_recover(data).is_err());
+
+        let mut truncated = Quack::initialize_assume_zeroed(vec![0u8; 256], 4).unwrap();
+        truncated.write(0, b"hello").unwrap();
+        let mut data = truncated.into_inner();
+        data.truncate(60);
+        assert!(Quack::open_mut(data).is_err());
+        assert!(Quack::open_mut(vec![0u8; 8]).is_err());
+    }
+
+    #[test]
+    fn remaining_space() {
+        let size = calculate_store_size(4, [5, 5]).unwrap();
+        let mut quack = Quack::initialize_assume_zeroed(vec![0u8; size as usize], 4).unwrap();
+        assert_eq!(quack.capacity().unwrap(), 2 * (16 + 5));
+        assert!(quack.try_reserve(2, 10).is_ok());
+        assert!(quack.try_reserve(3, 10).is_err());
+        quack.write(0, b"hello").unwrap();
+        assert_eq!(quack.used().unwrap(), 16 + 5);
+        assert_eq!(quack.remaining().unwrap(), 16 + 5);
+        assert!(quack.can_fit(5).unwrap());
+        assert!(!quack.can_fit(6).unwrap());
+        quack.write(3, b"world").unwrap();
+        assert_eq!(quack.remaining().unwrap(), 0);
+        assert!(!quack.can_fit(0).unwrap());
+        assert!(quack.try_reserve(0, 0).is_ok());
+    }
+
+    #[test]
+    fn remaining_space_sparse() {
+        let mut builder = QuackBuilder::new(4).sparse_slots(true);
+        builder.insert(0, b"hello").unwrap();
+        let size = builder.size().unwrap();
+        let mut quack = builder.build_into(vec![0u8; size as usize + 64]).unwrap();
+        assert!(quack.can_fit(5).unwrap());
+        assert!(quack.can_write(0, 5).unwrap());
+        // slot 1 wasn't occupied when built, so has no room for an offset
+        assert!(!quack.can_write(1, 5).unwrap());
+        assert!(quack.write(1, b"world").is_err());
+        quack.write(0, b"world").unwrap();
+    }
+
+    #[test]
+    fn remaining_space_compact() {
+        // a sparse file, so only the pages touched take up memory
+        let file = tempfile::tempfile().unwrap();
+        file.set_len(5 << 30).unwrap();
+        // SAFETY: the file is ours alone.
+        let mmap = unsafe { memmap2::MmapMut::map_mut(&file).unwrap() };
+        let format = Format::new().with_elements(ElementEncoding::Compact);
+        let mut quack = format.initialize_assume_zeroed(mmap, 4).unwrap();
+        let layout = quack.layout().unwrap();
+        let store_len = u64::from(u32::MAX) - layout.store_start().unwrap() - 50;
+        layout
+            .write_store_len(quack.data.as_mut(), store_len)
+            .unwrap();
+
+        assert!(quack.can_fit(10).unwrap());
+        assert!(!quack.can_fit(100).unwrap());
+        // the second write lands past 4 GiB, so nothing can link to it
+        quack.write(0, &[0; 100]).unwrap();
+        quack.write(0, &[0; 100]).unwrap();
+        assert!(!quack.can_fit(1).unwrap());
+        assert!(!quack.can_write(0, 1).unwrap());
+        assert!(quack.write(0, b"x").is_err());
+    }
 }
@@ -0,0 +1,263 @@
+use std::io::{self, Read, Write};
+
+use crate::{Format, OutaBounds, Quack, QuackBuilder, invalid_data, read_u64, stor, val};
+
+impl<B: AsRef<[u8]>> Quack<B> {
+    /// Whether this quack is in [crate::Format::LEGACY], the header-less
+    /// layout with num_slots and store_len at offsets 0 and 8.
+    pub fn is_legacy(&self) -> Result<bool, OutaBounds> {
+        Ok(!self.format()?.is_versioned())
+    }
+
+    /// Inserts every value into `builder`, configured with the format options
+    /// to migrate to and the same number of slots.
+    ///
+    /// Check the result with [Quack::equivalent] before replacing the original.
+    pub fn migrate_into(&self, mut builder: QuackBuilder) -> Result<QuackBuilder, OutaBounds> {
+        merge_layers([self], false, &mut builder, |_, _| Ok(()))?;
+        Ok(builder)
+    }
+
+    /// Rebuilds this quack with `builder`, see [Quack::migrate_into], and
+    /// checks the result is intact and reads the same as this quack.
+    pub fn migrate(&self, builder: QuackBuilder) -> Result<Quack<Vec<u8>>, OutaBounds> {
+        let migrated = self.migrate_into(builder)?.build()?;
+        migrated.verify()?;
+        if !self.equivalent(&migrated)? {
+            return Err(OutaBounds);
+        }
+        Ok(migrated)
+    }
+
+    /// Whether `other` has as many slots as this quack, and reads the same
+    /// decoded values in the same order from every one of them, whatever the
+    /// formats of the two.
+    pub fn equivalent<C: AsRef<[u8]>>(&self, other: &Quack<C>) -> Result<bool, OutaBounds> {
+        if self.slots()? != other.slots()? {
+            return Ok(false);
+        }
+        let mut theirs = other.iter_slots()?;
+        for slot in self.iter_slots()? {
+            let (slot, sequence) = slot?;
+            let Some((other_slot, other_sequence)) = theirs.next().transpose()? else {
+                return Ok(false);
+            };
+            if slot != other_slot {
+                return Ok(false);
+            }
+            let (mut ours, mut theirs) = (sequence, other_sequence);
+            loop {
+                match (ours.try_next_decoded()?, theirs.try_next_decoded()?) {
+                    (None, None) => break,
+                    (Some(a), Some(b)) if a == b => {}
+                    _ => return Ok(false),
+                }
+            }
+        }
+        Ok(theirs.next().is_none())
+    }
+}
+
+/// Rewrites the legacy quack read from `input` in [Format::new], the default
+/// versioned format, writing it to `output` a piece at a time, so quacks of
+/// any size migrate in bounded memory. The two formats store elements alike,
+/// so only the header grows and every offset moves up by as much. The result
+/// keeps the spare capacity of the original. Returns the number of bytes
+/// written.
+///
+/// The output isn't checked. Like with [Quack::migrate_into], open it and check
+/// it with [Quack::verify] and [Quack::equivalent] before replacing the
+/// original, as [Quack::migrate] does.
+///
+/// Migrating to other formats lays the values out anew, see
+/// [Quack::migrate_into].
+pub fn upgrade_legacy(mut input: impl Read, mut output: impl Write) -> io::Result<u64> {
+    let mut header = [0; Format::LEGACY.header_len() as usize];
+    input.read_exact(&mut header)?;
+    let legacy = stor::Layout::read(&header).map_err(invalid_data)?;
+    if legacy.format.is_versioned() {
+        return Err(invalid_data(OutaBounds));
+    }
+    let store_len = legacy.read_store_len(&header).map_err(invalid_data)?;
+    let layout = stor::Layout::new(Format::new(), legacy.num_slots);
+    let (old_start, new_start) = (
+        legacy.store_start().map_err(invalid_data)?,
+        layout.store_start().map_err(invalid_data)?,
+    );
+    let old_end = old_start.checked_add(store_len);
+    let remap = |offset: u64| match offset {
+        0 => Ok(0),
+        _ if offset >= old_start && old_end.is_some_and(|end| offset < end) => {
+            Ok(offset - old_start + new_start)
+        }
+        _ => Err(invalid_data(OutaBounds)),
+    };
+
+    let mut new_header = [0; stor::HEADER_LEN as usize];
+    layout.write(&mut new_header).map_err(invalid_data)?;
+    layout
+        .write_store_len(&mut new_header, store_len)
+        .map_err(invalid_data)?;
+    output.write_all(&new_header)?;
+    let mut written = new_header.len() as u64;
+
+    let mut slots = [0; 8 * 1024];
+    let mut slots_left = legacy.num_slots;
+    while slots_left > 0 {
+        let n = slots_left.min(slots.len() as u64 / 8) as usize;
+        let chunk = &mut slots[..n * 8];
+        input.read_exact(chunk)?;
+        for slot in chunk.chunks_exact_mut(8) {
+            let head = remap(read_u64(slot, 0).map_err(invalid_data)?)?;
+            slot.copy_from_slice(&head.to_be_bytes());
+        }
+        output.write_all(chunk)?;
+        written += chunk.len() as u64;
+        slots_left -= n as u64;
+    }
+
+    let mut start = old_start;
+    while start - old_start < store_len {
+        let mut element = [0; val::PAYLOAD_START as usize];
+        input.read_exact(&mut element)?;
+        let old = val::read_header(&element, Format::LEGACY, start).map_err(invalid_data)?;
+        let end = start
+            .checked_add(old.payload_start)
+            .and_then(|start| start.checked_add(old.payload_len))
+            .filter(|end| end - old_start <= store_len)
+            .ok_or_else(|| invalid_data(OutaBounds))?;
+        let (new, new_len) = val::header(
+            layout.format,
+            start - old_start + new_start,
+            remap(old.next)?,
+            old.payload_len,
+            old.tags,
+        )
+        .map_err(invalid_data)?;
+        output.write_all(&new[..new_len])?;
+        if io::copy(&mut (&mut input).take(old.payload_len), &mut output)? != old.payload_len {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        written += new_len as u64 + old.payload_len;
+        start = end;
+    }
+
+    // past store_len is only spare room, so it isn't copied, just kept
+    let spare = io::copy(&mut input, &mut io::sink())?;
+    written += io::copy(&mut io::repeat(0).take(spare), &mut output)?;
+    Ok(written)
+}
+
+/// Inserts the values of `layers`, oldest first, into `builder` slot by slot,
+/// in the order reads chain them: newest layer first and, when `shadowing`,
+/// from the newest layer holding anything only. Every layer must have as many
+/// slots as the builder. `newer` is called after each slot to insert values
+/// that come before all of the layers'.
+pub(crate) fn merge_layers<'a, B: AsRef<[u8]> + 'a>(
+    layers: impl IntoIterator<Item = &'a Quack<B>>,
+    shadowing: bool,
+    builder: &mut QuackBuilder,
+    mut newer: impl FnMut(&mut QuackBuilder, u64) -> Result<(), OutaBounds>,
+) -> Result<(), OutaBounds> {
+    let num_slots = builder.num_slots();
+    let mut layers = layers
+        .into_iter()
+        .map(|layer| {
+            if layer.slots()? != num_slots {
+                return Err(OutaBounds);
+            }
+            Ok(layer.iter_slots()?.peekable())
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut values = Vec::new();
+    for slot in 0..num_slots {
+        for layer in layers.iter_mut().rev() {
+            // empty slots are skipped, errors are taken to be returned
+            let Some(next) = layer.next_if(|next| next.as_ref().map_or(true, |(s, _)| *s == slot))
+            else {
+                continue;
+            };
+            let (_, mut sequence) = next?;
+            if shadowing && !values.is_empty() {
+                continue;
+            }
+            while let Some(v) = sequence.try_next_decoded()? {
+                values.push(v);
+            }
+        }
+        // newest first, like reads, so reversed into insertion order
+        for v in values.drain(..).rev() {
+            builder.insert(slot, &v)?;
+        }
+        newer(builder, slot)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ElementEncoding, calculate_store_size, write_u64};
+
+    #[test]
+    fn upgrade_legacy_in_pieces() {
+        let size = calculate_store_size(4, [5, 5, 5, 1, 100]).unwrap();
+        let mut legacy = Quack::initialize_assume_zeroed(vec![0u8; size as usize], 4).unwrap();
+        for (k, v) in [(0, &b"hello"[..]), (5, b"world"), (0, b"again"), (3, b"!")] {
+            legacy.write(k, v).unwrap();
+        }
+
+        let mut upgraded = Vec::new();
+        let written = upgrade_legacy(legacy.ref_inner().as_slice(), &mut upgraded).unwrap();
+        assert_eq!(written, upgraded.len() as u64);
+        let mut upgraded = Quack::new(upgraded);
+        assert_eq!(upgraded.format().unwrap(), Format::new());
+        upgraded.verify().unwrap();
+        assert!(legacy.equivalent(&upgraded).unwrap());
+        assert_eq!(upgraded.remaining().unwrap(), legacy.remaining().unwrap());
+        upgraded.write(1, &[b'q'; 100]).unwrap();
+
+        // versioned quacks, and offsets outside the store, aren't upgraded
+        let mut sink = Vec::new();
+        assert!(upgrade_legacy(upgraded.ref_inner().as_slice(), &mut sink).is_err());
+        let mut data = legacy.into_inner();
+        write_u64(&mut data, 16, 1000).unwrap();
+        assert!(upgrade_legacy(data.as_slice(), &mut sink).is_err());
+    }
+
+    #[test]
+    fn migrate_legacy() {
+        let size = calculate_store_size(4, [5, 5, 5, 1]).unwrap();
+        let mut legacy = Quack::initialize_assume_zeroed(vec![0u8; size as usize], 4).unwrap();
+        for (k, v) in [(0, &b"hello"[..]), (5, b"world"), (0, b"again"), (3, b"!")] {
+            legacy.write(k, v).unwrap();
+        }
+        assert!(legacy.is_legacy().unwrap());
+
+        let builder = QuackBuilder::new(4)
+            .elements(ElementEncoding::Compact)
+            .dedup(true);
+        let migrated = legacy.migrate(builder).unwrap();
+        assert!(!migrated.is_legacy().unwrap());
+        assert_eq!(
+            migrated.format().unwrap().elements(),
+            ElementEncoding::Compact
+        );
+        assert_eq!(
+            migrated.read(0).unwrap().collect::<Vec<_>>(),
+            [&b"again"[..], b"hello"]
+        );
+        assert!(legacy.migrate(QuackBuilder::new(3)).is_err());
+
+        let mut other = QuackBuilder::new(4);
+        other.insert(0, b"hello").unwrap();
+        let other = other.build().unwrap();
+        assert!(!legacy.equivalent(&other).unwrap());
+        assert!(!other.equivalent(&legacy).unwrap());
+
+        // a damaged quack fails rather than migrating what it can read
+        let mut data = legacy.into_inner();
+        write_u64(&mut data, 69, 1000).unwrap();
+        assert!(Quack::new(data).migrate_into(QuackBuilder::new(4)).is_err());
+    }
+}
@@ -0,0 +1,277 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+use crate::migrate::merge_layers;
+use crate::{OutaBounds, Quack, QuackBuilder, Sequence, invalid_data, read_u64};
+
+/// Magic at the start of a log file.
+const LOG_MAGIC: [u8; 8] = *b"QUACKLOG";
+/// A record is a u64 key and a u64 value length, followed by the value.
+pub(crate) const RECORD_HEADER_LEN: usize = 2 * size_of::<u64>();
+
+/// A read-only base quack plus an append-only log of later writes.
+///
+/// Writes go to the log file, and to an in-memory copy of it indexed by slot.
+/// Reads return the logged values for a key, newest first, followed by the
+/// values in the base. Once the log grows large, [Overlay::merge_into] folds
+/// both into a fresh, optimized base.
+///
+/// Log records are a big-endian u64 key, a big-endian u64 value length, and
+/// the value. A record cut short by a crash is dropped when the log is opened.
+pub struct Overlay<B> {
+    base: Quack<B>,
+    num_slots: u64,
+    log: File,
+    /// Contents of the log file.
+    records: Vec<u8>,
+    /// Offsets of logged values in `records`, by slot, oldest first.
+    index: HashMap<u64, Vec<(usize, usize)>>,
+}
+
+impl<B: AsRef<[u8]>> Overlay<B> {
+    /// Opens the log at `path` on top of `base`, creating it if needed.
+    pub fn open(base: Quack<B>, path: impl AsRef<Path>) -> io::Result<Self> {
+        let num_slots = base.slots().map_err(invalid_data)?;
+        let mut log = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(path)?;
+        let mut records = Vec::new();
+        log.read_to_end(&mut records)?;
+        if records.is_empty() {
+            log.write_all(&LOG_MAGIC)?;
+            records.extend_from_slice(&LOG_MAGIC);
+        } else if !records.starts_with(&LOG_MAGIC) {
+            return Err(invalid_data(OutaBounds));
+        }
+
+        let mut overlay = Overlay {
+            base,
+            num_slots,
+            log,
+            records,
+            index: HashMap::new(),
+        };
+        let mut start = LOG_MAGIC.len();
+        while let Some((k, value_start, value_len)) = read_record(&overlay.records, start) {
+            overlay.index_value(k, value_start, value_len)?;
+            start = value_start + value_len;
+        }
+        if start < overlay.records.len() {
+            overlay.records.truncate(start);
+            overlay.log.set_len(start as u64)?;
+        }
+        Ok(overlay)
+    }
+
+    /// Appends an item for a given key to the log. Like any file write it is
+    /// only durable once [Overlay::sync] returns.
+    pub fn write(&mut self, k: u64, v: &[u8]) -> io::Result<()> {
+        let start = self.records.len();
+        write_record(&mut self.records, k, v);
+        if let Err(e) = self.log.write_all(&self.records[start..]) {
+            // don't leave part of the record behind for later writes to follow
+            self.records.truncate(start);
+            self.log.set_len(start as u64)?;
+            return Err(e);
+        }
+        self.index_value(k, start + RECORD_HEADER_LEN, v.len())
+    }
+
+    /// Flushes logged writes to disk.
+    pub fn sync(&self) -> io::Result<()> {
+        self.log.sync_data()
+    }
+
+    /// The values for a given key, those in the log first.
+    pub fn read(&self, k: u64) -> Result<OverlaySequence<'_>, OutaBounds> {
+        let slot = k.checked_rem(self.num_slots).ok_or(OutaBounds)?;
+        let logged = self.index.get(&slot).map_or(&[][..], Vec::as_slice);
+        Ok(OverlaySequence {
+            records: &self.records,
+            logged: logged.iter(),
+            base: self.base.read(k)?,
+        })
+    }
+
+    /// Number of writes in the log.
+    pub fn logged(&self) -> usize {
+        self.index.values().map(Vec::len).sum()
+    }
+
+    pub fn base(&self) -> &Quack<B> {
+        &self.base
+    }
+
+    /// Inserts everything in the base and then the log into `builder`, which
+    /// must have as many slots as the base.
+    ///
+    /// Afterwards the log can be removed and the built quack used as the new base.
+    pub fn merge_into(&self, mut builder: QuackBuilder) -> Result<QuackBuilder, OutaBounds> {
+        merge_layers([&self.base], false, &mut builder, |builder, slot| {
+            for (start, len) in self.index.get(&slot).into_iter().flatten() {
+                builder.insert(slot, &self.records[*start..*start + *len])?;
+            }
+            Ok(())
+        })?;
+        Ok(builder)
+    }
+
+    fn index_value(&mut self, k: u64, start: usize, len: usize) -> io::Result<()> {
+        let slot = k
+            .checked_rem(self.num_slots)
+            .ok_or_else(|| invalid_data(OutaBounds))?;
+        self.index.entry(slot).or_default().push((start, len));
+        Ok(())
+    }
+}
+
+/// An iterator over the values for a key in an [Overlay], newest first.
+///
+/// Logged values are always as written, while values in the base are
+/// decompressed, like [Sequence] does.
+pub struct OverlaySequence<'a> {
+    records: &'a [u8],
+    logged: core::slice::Iter<'a, (usize, usize)>,
+    base: Sequence<'a>,
+}
+
+impl<'a> Iterator for OverlaySequence<'a> {
+    type Item = Cow<'a, [u8]>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.try_next_decoded().ok().unwrap_or(None)
+    }
+}
+
+impl<'a> OverlaySequence<'a> {
+    /// The next logged value, or the next value in the base as stored, see
+    /// [Sequence::try_next].
+    pub fn try_next(&mut self) -> Result<Option<&'a [u8]>, OutaBounds> {
+        match self.next_logged() {
+            Some(v) => Ok(Some(v)),
+            None => self.base.try_next(),
+        }
+    }
+
+    /// Like [OverlaySequence::try_next], but decodes values from the base, see
+    /// [Sequence::try_next_decoded].
+    pub fn try_next_decoded(&mut self) -> Result<Option<Cow<'a, [u8]>>, OutaBounds> {
+        match self.next_logged() {
+            Some(v) => Ok(Some(Cow::Borrowed(v))),
+            None => self.base.try_next_decoded(),
+        }
+    }
+
+    fn next_logged(&mut self) -> Option<&'a [u8]> {
+        let (start, len) = self.logged.next_back()?;
+        Some(&self.records[*start..*start + *len])
+    }
+}
+
+/// Appends a record for `k` and `v` to `out`.
+pub(crate) fn write_record(out: &mut Vec<u8>, k: u64, v: &[u8]) {
+    out.extend_from_slice(&k.to_be_bytes());
+    out.extend_from_slice(&(v.len() as u64).to_be_bytes());
+    out.extend_from_slice(v);
+}
+
+/// Parses the record at `start`, returning its key and where its value lies.
+/// Returns None if there isn't a whole record there.
+pub(crate) fn read_record(data: &[u8], start: usize) -> Option<(u64, usize, usize)> {
+    let k = read_u64(data, start as u64).ok()?;
+    let len = read_u64(data, start.checked_add(8)? as u64).ok()?;
+    let value_start = start.checked_add(RECORD_HEADER_LEN)?;
+    let len = usize::try_from(len).ok()?;
+    (value_start.checked_add(len)? <= data.len()).then_some((k, value_start, len))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{stor, write_u64};
+
+    fn base() -> Quack<Vec<u8>> {
+        let mut builder = QuackBuilder::new(4);
+        builder.insert(0, b"hello").unwrap();
+        builder.insert(1, b"world").unwrap();
+        builder.insert(0, b"again").unwrap();
+        builder.build().unwrap()
+    }
+
+    #[test]
+    fn log_then_base() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("quack.log");
+
+        let mut overlay = Overlay::open(base(), &path).unwrap();
+        overlay.write(0, b"newer").unwrap();
+        overlay.write(4, b"newest").unwrap();
+        overlay.write(2, b"quack").unwrap();
+        overlay.sync().unwrap();
+
+        let items = overlay.read(0).unwrap().collect::<Vec<_>>();
+        assert_eq!(items, [&b"newest"[..], b"newer", b"again", b"hello"]);
+        let items = overlay.read(1).unwrap().collect::<Vec<_>>();
+        assert_eq!(items, [&b"world"[..]]);
+
+        // a crash midway through a write leaves a partial record behind
+        drop(overlay);
+        let mut log = OpenOptions::new().append(true).open(&path).unwrap();
+        log.write_all(&[0, 0, 0]).unwrap();
+
+        let overlay = Overlay::open(base(), &path).unwrap();
+        assert_eq!(overlay.logged(), 3);
+        let items = overlay.read(2).unwrap().collect::<Vec<_>>();
+        assert_eq!(items, [&b"quack"[..]]);
+    }
+
+    #[test]
+    fn merge() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut overlay = Overlay::open(base(), dir.path().join("quack.log")).unwrap();
+        overlay.write(0, b"newer").unwrap();
+        overlay.write(3, b"quack").unwrap();
+
+        assert!(overlay.merge_into(QuackBuilder::new(5)).is_err());
+        let merged = overlay
+            .merge_into(QuackBuilder::new(4))
+            .unwrap()
+            .build()
+            .unwrap();
+        for k in 0..4 {
+            let expected = overlay.read(k).unwrap().collect::<Vec<_>>();
+            assert_eq!(merged.read(k).unwrap().collect::<Vec<_>>(), expected);
+        }
+
+        // a damaged base fails the merge rather than dropping values
+        let mut data = base().into_inner();
+        let head = stor::Layout::read(&data)
+            .and_then(|layout| layout.read_slot(&data, 0))
+            .unwrap();
+        write_u64(&mut data, head, 1 << 40).unwrap();
+        let overlay = Overlay::open(Quack::new(data), dir.path().join("damaged.log")).unwrap();
+        assert!(overlay.merge_into(QuackBuilder::new(4)).is_err());
+    }
+
+    #[cfg(feature = "lz4")]
+    #[test]
+    fn decoded() {
+        let mut builder = QuackBuilder::new(4).compression(crate::Compression::Lz4);
+        builder.insert(0, &[b'q'; 300]).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let mut overlay =
+            Overlay::open(builder.build().unwrap(), dir.path().join("quack.log")).unwrap();
+        overlay.write(0, &[b'l'; 300]).unwrap();
+
+        let mut seq = overlay.read(0).unwrap();
+        assert_eq!(seq.try_next().unwrap().unwrap(), [b'l'; 300]);
+        assert!(seq.try_next().unwrap().unwrap().len() < 300);
+        let decoded = overlay.read(0).unwrap().collect::<Vec<_>>();
+        assert_eq!(decoded, [&[b'l'; 300][..], &[b'q'; 300][..]]);
+    }
+}
@@ -0,0 +1,420 @@
+use core::ops::Range;
+use std::borrow::Cow;
+
+#[cfg(feature = "rayon")]
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
+
+use crate::{Codec, OutaBounds, QuackSnapshot, Sequence, compress, stor, val};
+
+impl<'a> QuackSnapshot<'a> {
+    /// Every non-empty slot with its list, in slot order. Unlike reading keys
+    /// `0..num_slots`, this doesn't rely on how keys map to slots.
+    pub fn iter_slots(&self) -> Slots<'a> {
+        self.iter_slot_range(0..self.layout.num_slots)
+    }
+
+    /// Like [QuackSnapshot::iter_slots], for the slots in `range` only.
+    pub fn iter_slot_range(&self, range: Range<u64>) -> Slots<'a> {
+        Slots {
+            snapshot: *self,
+            range: range.start..range.end.min(self.layout.num_slots),
+        }
+    }
+
+    /// Splits the slots into up to `n` contiguous ranges, covering every slot,
+    /// each holding about as much as the others by the measure `balance`
+    /// picks. Scan them with [QuackSnapshot::iter_slot_range], one per thread.
+    pub fn slot_ranges(&self, n: usize, balance: Balance) -> Result<Vec<Range<u64>>, OutaBounds> {
+        let num_slots = self.layout.num_slots;
+        let n = (n.max(1) as u64).min(num_slots.max(1));
+        let mut weights = Vec::new();
+        match balance {
+            Balance::Slots => {}
+            Balance::ChainLengths => {
+                for slot in self.iter_slots() {
+                    let (slot_index, mut sequence) = slot?;
+                    let mut len = 0;
+                    while sequence.try_next_element()?.is_some() {
+                        len += 1;
+                    }
+                    weights.push((slot_index, len));
+                }
+            }
+            Balance::StoreOffsets => {
+                let mut heads = Vec::new();
+                for slot_index in 0..num_slots {
+                    let head = self.layout.read_slot(self.data, slot_index)?;
+                    if head != 0 && head < self.watermark {
+                        heads.push((slot_index, head));
+                    }
+                }
+                let ends = heads.iter().skip(1).map(|(_, head)| *head);
+                for ((slot_index, head), end) in heads.iter().zip(ends.chain([self.watermark])) {
+                    weights.push((*slot_index, end.saturating_sub(*head)));
+                }
+            }
+        }
+
+        let total: u64 = weights.iter().map(|(_, weight)| weight).sum();
+        if total == 0 {
+            let per_range = num_slots.div_ceil(n).max(1);
+            return Ok((0..num_slots.max(1))
+                .step_by(per_range as usize)
+                .map(|start| start..(start + per_range).min(num_slots))
+                .collect());
+        }
+        let mut ranges = Vec::new();
+        let (mut start, mut acc) = (0, 0);
+        for (slot_index, weight) in weights {
+            acc += weight;
+            let cut = ranges.len() as u64 + 1;
+            let target = u128::from(total) * u128::from(cut) / u128::from(n);
+            if u128::from(acc) >= target && cut < n {
+                ranges.push(start..slot_index + 1);
+                start = slot_index + 1;
+            }
+        }
+        if start < num_slots || ranges.is_empty() {
+            ranges.push(start..num_slots);
+        }
+        Ok(ranges)
+    }
+
+    /// Every value, decoded, along with the index of its slot. Slots come in
+    /// order, and each slot's values newest first, as [Sequence] yields them.
+    pub fn iter_entries(&self) -> Entries<'a> {
+        self.iter_slots().entries()
+    }
+
+    /// Every element, in the order they are stored in. Walks memory front to
+    /// back, which is the fastest way to look at everything, but elements
+    /// don't know their slot.
+    ///
+    /// A crash during [crate::Quack::write_shared] can leave store space that
+    /// was reserved but never written. The zeroes there read as empty elements
+    /// that belong to no slot, and where the gap doesn't split evenly into
+    /// those the scan loses its place.
+    pub fn scan_store(&self) -> Result<StoreScan<'a>, OutaBounds> {
+        let layout = &self.layout;
+        let dictionary_end = layout
+            .dictionary_start
+            .checked_add(layout.dictionary_len)
+            .ok_or(OutaBounds)?;
+        Ok(StoreScan {
+            data: self.data,
+            layout: self.layout,
+            offset: layout.store_start()?,
+            end: self.watermark,
+            dictionary: layout.dictionary_start..dictionary_end,
+            prepared: self.dictionary,
+        })
+    }
+}
+
+#[cfg(feature = "rayon")]
+impl<'a> QuackSnapshot<'a> {
+    /// [QuackSnapshot::iter_slots] spread over rayon's thread pool, in a few
+    /// slot ranges per thread balanced by `balance`.
+    pub fn par_iter_slots(
+        &self,
+        balance: Balance,
+    ) -> Result<impl ParallelIterator<Item = Result<(u64, Sequence<'a>), OutaBounds>>, OutaBounds>
+    {
+        let snapshot = *self;
+        Ok(self
+            .par_ranges(balance)?
+            .into_par_iter()
+            .flat_map_iter(move |range| snapshot.iter_slot_range(range)))
+    }
+
+    /// [QuackSnapshot::iter_entries] spread over rayon's thread pool, see
+    /// [QuackSnapshot::par_iter_slots].
+    pub fn par_iter_entries(
+        &self,
+        balance: Balance,
+    ) -> Result<impl ParallelIterator<Item = <Entries<'a> as Iterator>::Item>, OutaBounds> {
+        let snapshot = *self;
+        Ok(self
+            .par_ranges(balance)?
+            .into_par_iter()
+            .flat_map_iter(move |range| snapshot.iter_slot_range(range).entries()))
+    }
+
+    fn par_ranges(&self, balance: Balance) -> Result<Vec<Range<u64>>, OutaBounds> {
+        // a few ranges per thread, so threads finishing early can steal work
+        self.slot_ranges(rayon::current_num_threads() * 4, balance)
+    }
+}
+
+/// How [QuackSnapshot::slot_ranges] weighs slots against each other.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum Balance {
+    /// The same number of slots per range. Free to compute.
+    #[default]
+    Slots,
+    /// The same number of values per range. Walks every chain to count them.
+    ChainLengths,
+    /// The same number of store bytes per range, going by where each chain
+    /// starts. Only reads the slots array, but assumes chains are stored
+    /// contiguously in slot order, as [crate::QuackBuilder] lays them out.
+    StoreOffsets,
+}
+
+/// An iterator over the non-empty slots of a quack, see [QuackSnapshot::iter_slots].
+pub struct Slots<'a> {
+    snapshot: QuackSnapshot<'a>,
+    range: Range<u64>,
+}
+
+impl<'a> Slots<'a> {
+    /// The values in these slots, see [QuackSnapshot::iter_entries].
+    pub fn entries(self) -> Entries<'a> {
+        Entries {
+            slots: self,
+            current: None,
+        }
+    }
+}
+
+impl<'a> Iterator for Slots<'a> {
+    type Item = Result<(u64, Sequence<'a>), OutaBounds>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        for slot_index in self.range.by_ref() {
+            let sequence = match self.snapshot.slot(slot_index) {
+                Ok(sequence) => sequence,
+                Err(e) => return Some(Err(e)),
+            };
+            match sequence.clone().try_next() {
+                Ok(Some(_)) => return Some(Ok((slot_index, sequence))),
+                Ok(None) => {}
+                Err(e) => return Some(Err(e)),
+            }
+        }
+        None
+    }
+}
+
+/// An iterator over every value in a quack, see [QuackSnapshot::iter_entries].
+pub struct Entries<'a> {
+    slots: Slots<'a>,
+    current: Option<(u64, Sequence<'a>)>,
+}
+
+impl<'a> Iterator for Entries<'a> {
+    type Item = Result<(u64, Cow<'a, [u8]>), OutaBounds>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some((slot_index, sequence)) = &mut self.current {
+                match sequence.try_next_decoded() {
+                    Ok(Some(value)) => return Some(Ok((*slot_index, value))),
+                    Ok(None) => self.current = None,
+                    Err(e) => return Some(Err(e)),
+                }
+            }
+            match self.slots.next()? {
+                Ok(slot) => self.current = Some(slot),
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
+
+/// An iterator over the elements of a quack in store order, see
+/// [QuackSnapshot::scan_store].
+pub struct StoreScan<'a> {
+    data: &'a [u8],
+    layout: stor::Layout,
+    offset: u64,
+    end: u64,
+    dictionary: Range<u64>,
+    prepared: &'a compress::Dictionary,
+}
+
+impl<'a> Iterator for StoreScan<'a> {
+    type Item = Result<StoreElement<'a>, OutaBounds>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.offset == self.dictionary.start && !self.dictionary.is_empty() {
+            self.offset = self.dictionary.end;
+        }
+        if self.offset >= self.end {
+            return None;
+        }
+        let element = self.element();
+        if element.is_err() {
+            // don't keep going through whatever follows
+            self.offset = self.end;
+        }
+        Some(element)
+    }
+}
+
+impl<'a> StoreScan<'a> {
+    fn element(&mut self) -> Result<StoreElement<'a>, OutaBounds> {
+        let format = self.layout.format;
+        let stored = val::read(self.data, format, self.offset)?;
+        let resolved = val::resolve(self.data, format, self.offset)?;
+        let element = StoreElement {
+            offset: self.offset,
+            value: resolved.payload,
+            compressed: resolved.tags.compressed,
+            shared: stored.tags.shared,
+            codec: format.codec(),
+            dictionary: self.layout.dictionary(self.data)?,
+            prepared: self.prepared,
+        };
+        self.offset = self
+            .offset
+            .checked_add(format.element_size(stored.payload.len() as u64)?)
+            .ok_or(OutaBounds)?;
+        Ok(element)
+    }
+}
+
+/// An element found by [QuackSnapshot::scan_store].
+pub struct StoreElement<'a> {
+    /// Where the element starts in the buffer.
+    pub offset: u64,
+    /// The value as stored, with shared payloads resolved.
+    pub value: &'a [u8],
+    /// Whether `value` is compressed, see [StoreElement::decoded].
+    pub compressed: bool,
+    /// Whether the element refers to another element's payload. Each payload
+    /// is also scanned where it is stored, so skip these to see it only once.
+    pub shared: bool,
+    codec: Codec,
+    dictionary: &'a [u8],
+    prepared: &'a compress::Dictionary,
+}
+
+impl<'a> StoreElement<'a> {
+    /// The value, decompressed if need be.
+    pub fn decoded(&self) -> Result<Cow<'a, [u8]>, OutaBounds> {
+        if !self.compressed {
+            return Ok(Cow::Borrowed(self.value));
+        }
+        let mut buf = Vec::new();
+        let dictionary = || Ok(Cow::Borrowed(self.dictionary));
+        compress::Decompressor::new(self.prepared)
+            .decompress_into(self.codec, dictionary, self.value, &mut buf)?;
+        Ok(Cow::Owned(buf))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Format, QuackBuilder};
+
+    #[test]
+    fn scans_agree() {
+        let mut builder = QuackBuilder::new(8).dedup(true);
+        let mut quack = Format::new()
+            .initialize_assume_zeroed(vec![0u8; 1024], 8)
+            .unwrap();
+        let long = "hello, quack!";
+        for (k, v) in [(3, long), (11, "world"), (5, "again"), (3, long)] {
+            builder.insert(k, v.as_bytes()).unwrap();
+            quack.write(k, v.as_bytes()).unwrap();
+        }
+
+        let slots = quack
+            .iter_slots()
+            .unwrap()
+            .map(|slot| slot.map(|(i, seq)| (i, seq.count())))
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(slots, [(3, 3), (5, 1)]);
+
+        let long = long.as_bytes();
+        let expected: [(u64, &[u8]); 4] = [(3, long), (3, b"world"), (3, long), (5, b"again")];
+        let built = builder.build().unwrap();
+        for quack in [&quack, &built] {
+            let entries = quack.iter_entries().unwrap().map(|entry| {
+                let (slot_index, value) = entry.unwrap();
+                (slot_index, value.into_owned())
+            });
+            assert!(entries.eq(expected.map(|(i, v)| (i, v.to_vec()))));
+        }
+
+        // written one at a time, elements are stored oldest first
+        let stored = quack
+            .scan_store()
+            .unwrap()
+            .map(|element| element.unwrap().value)
+            .collect::<Vec<_>>();
+        assert_eq!(stored, [long, b"world", b"again", long]);
+
+        // the built quack stores the repeat as a reference, resolved when scanned
+        let built = built
+            .scan_store()
+            .unwrap()
+            .map(|element| element.map(|e| (e.value, e.shared)))
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(built.len(), 4);
+        assert_eq!(
+            built
+                .iter()
+                .filter(|(v, shared)| *shared && *v == long)
+                .count(),
+            1
+        );
+    }
+
+    #[test]
+    fn balanced_slot_ranges() {
+        let mut builder = QuackBuilder::new(100);
+        // slots 0..10 hold most of the data
+        for k in 0..1000u64 {
+            let slot = if k % 2 == 0 { k % 10 } else { k % 100 };
+            builder.insert(slot, &[0; 20]).unwrap();
+        }
+        let quack = builder.build().unwrap();
+
+        for balance in [Balance::Slots, Balance::ChainLengths, Balance::StoreOffsets] {
+            let ranges = quack.slot_ranges(4, balance).unwrap();
+            assert_eq!(ranges.len(), 4);
+            assert_eq!(ranges[0].start, 0);
+            assert_eq!(ranges[3].end, 100);
+            assert!(ranges.windows(2).all(|w| w[0].end == w[1].start));
+
+            let counts = ranges
+                .iter()
+                .map(|range| {
+                    let snapshot = quack.snapshot().unwrap();
+                    snapshot.iter_slot_range(range.clone()).entries().count()
+                })
+                .collect::<Vec<_>>();
+            assert_eq!(counts.iter().sum::<usize>(), 1000);
+            if balance != Balance::Slots {
+                assert!(
+                    counts.iter().all(|count| (150..=400).contains(count)),
+                    "{counts:?}"
+                );
+            }
+        }
+        assert_eq!(quack.slot_ranges(1000, Balance::Slots).unwrap().len(), 100);
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn par_iter_entries() {
+        use rayon::iter::ParallelIterator;
+
+        let mut builder = QuackBuilder::new(64);
+        for k in 0..5000u64 {
+            builder.insert(k, &k.to_be_bytes()).unwrap();
+        }
+        let quack = builder.build().unwrap();
+        let snapshot = quack.snapshot().unwrap();
+        let sum: u64 = snapshot
+            .par_iter_entries(Balance::StoreOffsets)
+            .unwrap()
+            .map(|entry| u64::from_be_bytes(entry.unwrap().1[..].try_into().unwrap()))
+            .sum();
+        assert_eq!(sum, (0..5000).sum());
+    }
+}
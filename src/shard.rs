@@ -0,0 +1,259 @@
+use std::fmt::{self, Display, Formatter};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::{OutaBounds, Quack, QuackBuilder, Sequence, invalid_data};
+
+/// First line of a manifest.
+const MANIFEST_HEADER: &str = "quackmap-shards 1";
+
+/// Describes the files making up a [ShardedQuack].
+///
+/// Manifests are text: a header line, the number of shards, then one file
+/// name per line in shard order. Relative names are relative to the directory
+/// holding the manifest.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ShardManifest {
+    files: Vec<String>,
+}
+
+impl ShardManifest {
+    /// Fails if there are no files, or a name contains a line break.
+    pub fn new(files: Vec<String>) -> Result<Self, OutaBounds> {
+        if files.is_empty() || files.iter().any(|f| f.contains(['\n', '\r'])) {
+            return Err(OutaBounds);
+        }
+        Ok(ShardManifest { files })
+    }
+
+    /// `count` shards named `{prefix}-{index}.quack`.
+    pub fn numbered(prefix: &str, count: usize) -> Result<Self, OutaBounds> {
+        Self::new((0..count).map(|i| format!("{prefix}-{i}.quack")).collect())
+    }
+
+    pub fn parse(text: &str) -> Result<Self, OutaBounds> {
+        let mut lines = text.lines();
+        if lines.next() != Some(MANIFEST_HEADER) {
+            return Err(OutaBounds);
+        }
+        let count: usize = lines
+            .next()
+            .and_then(|count| count.parse().ok())
+            .ok_or(OutaBounds)?;
+        let files = lines.map(str::to_owned).collect::<Vec<_>>();
+        if files.len() != count {
+            return Err(OutaBounds);
+        }
+        Self::new(files)
+    }
+
+    pub fn read(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::parse(&fs::read_to_string(path)?).map_err(invalid_data)
+    }
+
+    pub fn write(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        fs::write(path, self.to_string())
+    }
+
+    pub fn shard_count(&self) -> usize {
+        self.files.len()
+    }
+
+    pub fn files(&self) -> &[String] {
+        &self.files
+    }
+
+    /// Paths of the shards, for a manifest stored in `dir`.
+    pub fn paths<'a>(&'a self, dir: &'a Path) -> impl Iterator<Item = PathBuf> + 'a {
+        self.files.iter().map(move |file| dir.join(file))
+    }
+}
+
+impl Display for ShardManifest {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{MANIFEST_HEADER}")?;
+        writeln!(f, "{}", self.files.len())?;
+        for file in &self.files {
+            writeln!(f, "{file}")?;
+        }
+        Ok(())
+    }
+}
+
+/// A quack split across several files.
+///
+/// Key k lives in shard `k % shard_count`, under the key `k / shard_count`.
+/// Dividing out the shard keeps the slots a shard's keys land in independent
+/// of which shard they are in, however the shard and slot counts relate. As
+/// with any quack, keys are expected to be hashes.
+///
+/// Shards can be left unopened, reads of keys routed to them fail.
+pub struct ShardedQuack<B> {
+    shards: Vec<Option<Quack<B>>>,
+}
+
+impl<B> ShardedQuack<B> {
+    /// Fails if there are no shards.
+    pub fn from_shards(shards: Vec<Option<Quack<B>>>) -> Result<Self, OutaBounds> {
+        if shards.is_empty() {
+            return Err(OutaBounds);
+        }
+        Ok(ShardedQuack { shards })
+    }
+
+    /// Opens every shard listed in the manifest at `path`, with `open`
+    /// turning a shard's path into its buffer, for example by mapping it.
+    pub fn open<F>(path: impl AsRef<Path>, open: F) -> io::Result<Self>
+    where
+        F: FnMut(&Path) -> io::Result<B>,
+    {
+        Self::open_some(path, |_| true, open)
+    }
+
+    /// Like [ShardedQuack::open], but only opens the shards `wanted` picks
+    /// by index.
+    pub fn open_some<W, F>(path: impl AsRef<Path>, mut wanted: W, mut open: F) -> io::Result<Self>
+    where
+        W: FnMut(usize) -> bool,
+        F: FnMut(&Path) -> io::Result<B>,
+    {
+        let path = path.as_ref();
+        let manifest = ShardManifest::read(path)?;
+        let dir = path.parent().unwrap_or(Path::new(""));
+        let shards = manifest
+            .paths(dir)
+            .enumerate()
+            .map(|(i, shard)| wanted(i).then(|| open(&shard).map(Quack::new)).transpose())
+            .collect::<io::Result<_>>()?;
+        Ok(ShardedQuack { shards })
+    }
+
+    pub fn shard_count(&self) -> usize {
+        self.shards.len()
+    }
+
+    /// The shard a key is stored in, and the key it is stored under there.
+    pub fn route(&self, k: u64) -> (usize, u64) {
+        route(self.shards.len(), k)
+    }
+
+    /// The shard at `index`, if it was opened.
+    pub fn shard(&self, index: usize) -> Option<&Quack<B>> {
+        self.shards.get(index)?.as_ref()
+    }
+
+    pub fn into_shards(self) -> Vec<Option<Quack<B>>> {
+        self.shards
+    }
+}
+
+impl<B: AsRef<[u8]>> ShardedQuack<B> {
+    pub fn read(&self, k: u64) -> Result<Sequence<'_>, OutaBounds> {
+        let (shard, k) = self.route(k);
+        self.shard(shard).ok_or(OutaBounds)?.read(k)
+    }
+}
+
+impl<B: AsMut<[u8]>> ShardedQuack<B> {
+    pub fn write(&mut self, k: u64, v: &[u8]) -> Result<(), OutaBounds> {
+        let (shard, k) = self.route(k);
+        let shard = self.shards[shard].as_mut().ok_or(OutaBounds)?;
+        shard.write(k, v)
+    }
+}
+
+/// Routes entries to one [QuackBuilder] per shard, routing keys like
+/// [ShardedQuack] does. The builders are independent, so each can be built
+/// on its own thread.
+pub struct ShardedBuilder {
+    builders: Vec<QuackBuilder>,
+}
+
+impl ShardedBuilder {
+    /// `shard_count` shards of `num_slots` slots each.
+    pub fn new(shard_count: usize, num_slots: u64) -> Result<Self, OutaBounds> {
+        Self::from_builders(
+            (0..shard_count)
+                .map(|_| QuackBuilder::new(num_slots))
+                .collect(),
+        )
+    }
+
+    /// Use the given, possibly differently configured, builders as shards.
+    pub fn from_builders(builders: Vec<QuackBuilder>) -> Result<Self, OutaBounds> {
+        if builders.is_empty() {
+            return Err(OutaBounds);
+        }
+        Ok(ShardedBuilder { builders })
+    }
+
+    pub fn insert(&mut self, k: u64, v: &[u8]) -> Result<(), OutaBounds> {
+        let (shard, k) = route(self.builders.len(), k);
+        self.builders[shard].insert(k, v)
+    }
+
+    /// The builders, in shard order.
+    pub fn into_builders(self) -> Vec<QuackBuilder> {
+        self.builders
+    }
+}
+
+fn route(shard_count: usize, k: u64) -> (usize, u64) {
+    let shard_count = shard_count as u64;
+    ((k % shard_count) as usize, k / shard_count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn build_and_open_shards() {
+        let dir = tempfile::tempdir().unwrap();
+        let manifest = ShardManifest::numbered("part", 3).unwrap();
+        let mut builder = ShardedBuilder::new(3, 4).unwrap();
+        for k in 0..24u64 {
+            builder.insert(k, &k.to_be_bytes()).unwrap();
+        }
+        let builders = builder.into_builders();
+        std::thread::scope(|s| {
+            for (builder, path) in builders.iter().zip(manifest.paths(dir.path())) {
+                s.spawn(move || fs::write(path, builder.build().unwrap().into_inner()).unwrap());
+            }
+        });
+        manifest.write(dir.path().join("quack.shards")).unwrap();
+
+        let sharded = ShardedQuack::open(dir.path().join("quack.shards"), |path: &Path| {
+            fs::read(path)
+        })
+        .unwrap();
+        assert_eq!(sharded.shard_count(), 3);
+        for k in 0..24u64 {
+            // every shard slot holds keys 12 apart, spread over all of its 4 slots
+            let items = sharded.read(k).unwrap().collect::<Vec<_>>();
+            let expected = [k % 12 + 12, k % 12].map(u64::to_be_bytes);
+            assert_eq!(items, expected.each_ref().map(|v| &v[..]));
+        }
+
+        let partial = ShardedQuack::open_some(
+            dir.path().join("quack.shards"),
+            |i| i == 1,
+            |path: &Path| fs::read(path),
+        )
+        .unwrap();
+        assert!(partial.read(0).is_err());
+        assert_eq!(partial.read(1).unwrap().count(), 2);
+    }
+
+    #[test]
+    fn manifest_round_trip() {
+        let manifest = ShardManifest::new(vec!["a.quack".into(), "b/c.quack".into()]).unwrap();
+        assert_eq!(
+            ShardManifest::parse(&manifest.to_string()).unwrap(),
+            manifest
+        );
+        assert!(ShardManifest::parse("quackmap-shards 1\n3\na\nb\n").is_err());
+        assert!(ShardManifest::new(vec!["a\nb".into()]).is_err());
+    }
+}
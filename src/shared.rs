@@ -0,0 +1,471 @@
+use core::slice;
+use core::sync::atomic::{AtomicU64, Ordering};
+use std::borrow::Cow;
+
+use crate::compress::{Decompressor, Dictionary};
+use crate::{ElementEncoding, OutaBounds, Quack, SlotWidth, read_uint, stor, val};
+
+/// Backings that can be viewed as atomic words, letting several threads write
+/// to and read from a quack at once, see [Quack::write_shared] and
+/// [Quack::read_shared].
+///
+/// The words must cover the whole buffer, and while it is shared all bytes of
+/// the buffer must be accessed through them.
+pub trait AtomicWords {
+    fn atomic_words(&self) -> &[AtomicU64];
+}
+
+impl AtomicWords for [AtomicU64] {
+    fn atomic_words(&self) -> &[AtomicU64] {
+        self
+    }
+}
+
+impl AtomicWords for Vec<AtomicU64> {
+    fn atomic_words(&self) -> &[AtomicU64] {
+        self
+    }
+}
+
+impl AtomicWords for Box<[AtomicU64]> {
+    fn atomic_words(&self) -> &[AtomicU64] {
+        self
+    }
+}
+
+impl<T: AtomicWords + ?Sized> AtomicWords for &T {
+    fn atomic_words(&self) -> &[AtomicU64] {
+        (**self).atomic_words()
+    }
+}
+
+/// A zeroed, 8 byte aligned heap buffer that can back a quack written to by
+/// several threads. It can be initialized like any other buffer, its bytes
+/// are only lent out while it isn't shared, through [AsMut].
+pub struct AtomicBuffer {
+    words: Box<[AtomicU64]>,
+}
+
+impl AtomicBuffer {
+    /// A buffer of at least `len` bytes, rounded up to a whole number of words.
+    pub fn zeroed(len: usize) -> Self {
+        let words = (0..len.div_ceil(size_of::<u64>()))
+            .map(|_| AtomicU64::new(0))
+            .collect();
+        AtomicBuffer { words }
+    }
+}
+
+impl AtomicWords for AtomicBuffer {
+    fn atomic_words(&self) -> &[AtomicU64] {
+        &self.words
+    }
+}
+
+impl AsMut<[u8]> for AtomicBuffer {
+    fn as_mut(&mut self) -> &mut [u8] {
+        let len = size_of_val(&*self.words);
+        // SAFETY: the words span exactly these bytes, and we have them to ourselves.
+        unsafe { slice::from_raw_parts_mut(self.words.as_mut_ptr() as *mut u8, len) }
+    }
+}
+
+impl<B: AtomicWords> Quack<B> {
+    /// Like [Quack::write], but through a shared reference, so any number of
+    /// threads can write at once. Only works on quacks using
+    /// [crate::Format::with_concurrent_access].
+    ///
+    /// Store space is reserved by bumping store_len. The bump is a compare and
+    /// swap rather than a plain add so a write that doesn't fit fails without
+    /// leaving store_len past the end of the buffer. The new element is then
+    /// linked in by swapping it into the slot, retrying if another writer got
+    /// there first.
+    ///
+    /// When a write fails for lack of space, so do all later writes larger than
+    /// the space left. Everything that can fail is checked before reserving, so
+    /// a failed write leaves no gap in the store. A crash between reserving and
+    /// writing the element still does, see [crate::QuackSnapshot::scan_store].
+    pub fn write_shared(&self, k: u64, v: &[u8]) -> Result<(), OutaBounds> {
+        let words = self.data.atomic_words();
+        let data_len = size_of_val(words) as u64;
+        let layout = self.shared_layout()?;
+        let slot = shared_slot(words, &layout, k)?;
+        let store_len = atomic_word(words, layout.store_len_offset())?;
+        let store_start = layout.store_start()?;
+        let element_size = layout.format.element_size(v.len() as u64)?;
+        // Other writers link to the new element, and it may link to any of
+        // theirs, so every element has to start where a next pointer can reach.
+        let max_head = match layout.format.elements() {
+            ElementEncoding::Wide => u64::MAX,
+            ElementEncoding::Compact => u64::from(u32::MAX),
+            ElementEncoding::Relative => {
+                if data_len.saturating_sub(store_start) > i32::MAX as u64 {
+                    return Err(OutaBounds);
+                }
+                u64::MAX
+            }
+        };
+
+        let reserved = store_len
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |len| {
+                let new_len = len.checked_add(element_size)?;
+                let end = store_start.checked_add(new_len)?;
+                (end <= data_len && store_start.checked_add(len)? <= max_head).then_some(new_len)
+            })
+            .map_err(|_| OutaBounds)?;
+        let new_head = store_start.checked_add(reserved).ok_or(OutaBounds)?;
+
+        // The reservation makes these bytes ours alone, and nobody reads them
+        // until they are published below.
+        let mut old_head = slot.load(Ordering::Acquire);
+        let (header, header_len) = val::header(
+            layout.format,
+            new_head,
+            old_head,
+            v.len() as u64,
+            val::Tags::default(),
+        )?;
+        store_bytes(words, new_head, &header[..header_len])?;
+        store_bytes(words, new_head + header_len as u64, v)?;
+        while let Err(head) =
+            slot.compare_exchange_weak(old_head, new_head, Ordering::Release, Ordering::Acquire)
+        {
+            old_head = head;
+            let (next, next_len) = val::next_bytes(layout.format, new_head, old_head)?;
+            store_bytes(words, new_head, &next[..next_len])?;
+        }
+        Ok(())
+    }
+
+    /// Reads the list for a given key, newest first, while other threads may
+    /// be writing with [Quack::write_shared]. Only works on quacks using
+    /// [crate::Format::with_concurrent_access].
+    ///
+    /// The buffer is only ever accessed through its atomic words, so values
+    /// are copied out rather than borrowed.
+    pub fn read_shared(&self, k: u64) -> Result<SharedSequence<'_>, OutaBounds> {
+        let snapshot = SharedSnapshot {
+            words: self.data.atomic_words(),
+            layout: self.shared_layout()?,
+            watermark: u64::MAX,
+            dictionary: &self.dictionary,
+        };
+        snapshot.read(k)
+    }
+
+    /// Like [Quack::snapshot], for reads alongside [Quack::write_shared].
+    /// The same limits apply: a write that had reserved its space but not yet
+    /// linked it in when the snapshot was taken shows up in the snapshot's
+    /// reads once it links.
+    pub fn snapshot_shared(&self) -> Result<SharedSnapshot<'_>, OutaBounds> {
+        let words = self.data.atomic_words();
+        let layout = self.shared_layout()?;
+        let store_len = atomic_word(words, layout.store_len_offset())?.load(Ordering::Acquire);
+        let watermark = layout
+            .store_start()?
+            .checked_add(store_len)
+            .ok_or(OutaBounds)?;
+        Ok(SharedSnapshot {
+            words,
+            layout,
+            watermark,
+            dictionary: &self.dictionary,
+        })
+    }
+
+    /// The header, from a copy loaded through the atomic words.
+    fn shared_layout(&self) -> Result<stor::Layout, OutaBounds> {
+        let layout = match self.layout.get() {
+            Some(layout) => *layout,
+            None => {
+                let words = self.data.atomic_words();
+                let mut header = [0; stor::HEADER_LEN as usize];
+                let len = header.len().min(size_of_val(words));
+                load_bytes(words, 0, &mut header[..len])?;
+                let layout = stor::Layout::read(&header[..len])?;
+                *self.layout.get_or_init(|| layout)
+            }
+        };
+        // sparse slots are only ever built, never shared
+        let format = layout.format;
+        if !format.concurrent_access()
+            || format.slot_width() != SlotWidth::U64
+            || format.sparse_slots()
+        {
+            return Err(OutaBounds);
+        }
+        Ok(layout)
+    }
+}
+
+/// A point-in-time view of a quack shared between threads, see
+/// [Quack::snapshot_shared].
+#[derive(Clone, Copy)]
+pub struct SharedSnapshot<'a> {
+    words: &'a [AtomicU64],
+    layout: stor::Layout,
+    /// Elements at or past this offset were written after the snapshot.
+    watermark: u64,
+    dictionary: &'a Dictionary,
+}
+
+impl<'a> SharedSnapshot<'a> {
+    pub fn read(&self, k: u64) -> Result<SharedSequence<'a>, OutaBounds> {
+        let head = shared_slot(self.words, &self.layout, k)?.load(Ordering::Acquire);
+        // every element takes up at least this much, so a list can't be longer
+        let steps = size_of_val(self.words) as u64 / self.layout.format.element_size(0)?;
+        Ok(SharedSequence {
+            snapshot: *self,
+            next: head,
+            steps,
+            stored: Vec::new(),
+            decompressor: Decompressor::new(self.dictionary),
+        })
+    }
+
+    /// The store_len the snapshot was taken at.
+    pub fn store_len(&self) -> Result<u64, OutaBounds> {
+        self.watermark
+            .checked_sub(self.layout.store_start()?)
+            .ok_or(OutaBounds)
+    }
+
+    fn header(&self, start: u64) -> Result<val::Header, OutaBounds> {
+        let mut element = [0; val::MAX_OVERHEAD];
+        let len = (size_of_val(self.words) as u64)
+            .saturating_sub(start)
+            .min(element.len() as u64) as usize;
+        load_bytes(self.words, start, &mut element[..len])?;
+        val::read_header(&element[..len], self.layout.format, start)
+    }
+}
+
+/// An iterator over the values for a key in a quack shared between threads,
+/// newest first, see [Quack::read_shared]. Values come out decoded.
+pub struct SharedSequence<'a> {
+    snapshot: SharedSnapshot<'a>,
+    next: u64,
+    /// Elements left to look at before the list must be looping.
+    steps: u64,
+    /// Compressed payloads, before decoding.
+    stored: Vec<u8>,
+    decompressor: Decompressor<'a>,
+}
+
+impl Iterator for SharedSequence<'_> {
+    type Item = Vec<u8>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut buf = Vec::new();
+        self.try_next_into(&mut buf).ok()?.then_some(buf)
+    }
+}
+
+impl SharedSequence<'_> {
+    /// Writes the next value into `buf`, replacing its contents, decompressed
+    /// if it was stored compressed. Returns false once the list is exhausted.
+    pub fn try_next_into(&mut self, buf: &mut Vec<u8>) -> Result<bool, OutaBounds> {
+        let snapshot = self.snapshot;
+        let (mut start, mut header) = loop {
+            if self.next == 0 {
+                return Ok(false);
+            }
+            self.steps = self.steps.checked_sub(1).ok_or(OutaBounds)?;
+            let start = self.next;
+            let header = snapshot.header(start)?;
+            self.next = header.next;
+            if start < snapshot.watermark {
+                break (start, header);
+            }
+        };
+        if header.tags.shared {
+            let width = snapshot.layout.format.slot_width().bytes();
+            if header.payload_len != width {
+                return Err(OutaBounds);
+            }
+            let mut target = [0; size_of::<u64>()];
+            let target = &mut target[..width as usize];
+            load_bytes(snapshot.words, start + header.payload_start, target)?;
+            start = read_uint(target, 0, width)?;
+            header = snapshot.header(start)?;
+            if header.tags.shared {
+                return Err(OutaBounds);
+            }
+        }
+        let compressed = header.tags.compressed;
+        let out = if compressed {
+            &mut self.stored
+        } else {
+            &mut *buf
+        };
+        // fail on damaged lengths before allocating for them
+        let payload_start = start + header.payload_start;
+        word_range(snapshot.words, payload_start, header.payload_len)?;
+        out.clear();
+        out.resize(header.payload_len as usize, 0);
+        load_bytes(snapshot.words, payload_start, out)?;
+        if compressed {
+            let layout = snapshot.layout;
+            // only loaded if nothing has prepared the dictionary yet
+            let dictionary = || {
+                let mut dictionary =
+                    vec![0; usize::try_from(layout.dictionary_len).map_err(|_| OutaBounds)?];
+                load_bytes(snapshot.words, layout.dictionary_start, &mut dictionary)?;
+                Ok(Cow::Owned(dictionary))
+            };
+            self.decompressor.decompress_into(
+                layout.format.codec(),
+                dictionary,
+                &self.stored,
+                buf,
+            )?;
+        }
+        Ok(true)
+    }
+}
+
+/// The slot for `k`, as an atomic word.
+fn shared_slot<'a>(
+    words: &'a [AtomicU64],
+    layout: &stor::Layout,
+    k: u64,
+) -> Result<&'a AtomicU64, OutaBounds> {
+    let slot_index = k.checked_rem(layout.num_slots).ok_or(OutaBounds)?;
+    // only sparse quacks read anything to find a slot
+    let offset = layout.slot_offset(&[], slot_index)?.ok_or(OutaBounds)?;
+    atomic_word(words, offset)
+}
+
+fn atomic_word(words: &[AtomicU64], offset: u64) -> Result<&AtomicU64, OutaBounds> {
+    if !offset.is_multiple_of(size_of::<u64>() as u64) {
+        return Err(OutaBounds);
+    }
+    words
+        .get((offset / size_of::<u64>() as u64) as usize)
+        .ok_or(OutaBounds)
+}
+
+/// The words bytes `start..start + len` lie in, and where in the first of
+/// them the bytes start.
+fn word_range(
+    words: &[AtomicU64],
+    start: u64,
+    len: u64,
+) -> Result<(usize, &[AtomicU64]), OutaBounds> {
+    const WORD: u64 = size_of::<u64>() as u64;
+    let end = start
+        .checked_add(len)
+        .filter(|end| *end <= size_of_val(words) as u64)
+        .ok_or(OutaBounds)?;
+    let covering = words
+        .get((start / WORD) as usize..end.div_ceil(WORD) as usize)
+        .ok_or(OutaBounds)?;
+    Ok(((start % WORD) as usize, covering))
+}
+
+/// Copies the bytes at `start` into `out`, with relaxed loads of the words
+/// they lie in. Only bytes published with a release store, read after the
+/// matching acquire load, are sure to be up to date.
+fn load_bytes(words: &[AtomicU64], start: u64, out: &mut [u8]) -> Result<(), OutaBounds> {
+    let (mut skip, covering) = word_range(words, start, out.len() as u64)?;
+    let mut out = out;
+    for word in covering {
+        let bytes = word.load(Ordering::Relaxed).to_ne_bytes();
+        let len = (bytes.len() - skip).min(out.len());
+        let (now, rest) = out.split_at_mut(len);
+        now.copy_from_slice(&bytes[skip..skip + len]);
+        out = rest;
+        skip = 0;
+    }
+    Ok(())
+}
+
+/// Copies `bytes` to `start`, leaving the rest of the words they lie in as
+/// they are, since those may belong to other writers.
+fn store_bytes(words: &[AtomicU64], start: u64, bytes: &[u8]) -> Result<(), OutaBounds> {
+    let (mut skip, covering) = word_range(words, start, bytes.len() as u64)?;
+    let mut bytes = bytes;
+    for word in covering {
+        let mut mask = [0; size_of::<u64>()];
+        let mut value = [0; size_of::<u64>()];
+        let len = (mask.len() - skip).min(bytes.len());
+        let (now, rest) = bytes.split_at(len);
+        mask[skip..skip + len].fill(0xff);
+        value[skip..skip + len].copy_from_slice(now);
+        let (mask, value) = (u64::from_ne_bytes(mask), u64::from_ne_bytes(value));
+        if mask == u64::MAX {
+            word.store(value, Ordering::Relaxed);
+        } else {
+            word.fetch_and(!mask, Ordering::Relaxed);
+            word.fetch_or(value, Ordering::Relaxed);
+        }
+        bytes = rest;
+        skip = 0;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Format;
+
+    #[test]
+    fn write_shared_from_many_threads() {
+        // compact elements don't line up with words, so writers share them
+        for elements in [ElementEncoding::Wide, ElementEncoding::Compact] {
+            let format = Format::new()
+                .with_concurrent_access(true)
+                .with_elements(elements);
+            let threads = 8u64;
+            let writes = 1000u64;
+            let size = format
+                .store_size(16, (0..threads * writes).map(|_| 8))
+                .unwrap();
+            let quack = format
+                .initialize_assume_zeroed(AtomicBuffer::zeroed(size as usize), 16)
+                .unwrap();
+
+            std::thread::scope(|s| {
+                for t in 0..threads {
+                    let quack = &quack;
+                    s.spawn(move || {
+                        for i in 0..writes {
+                            let v = t * writes + i;
+                            quack.write_shared(v, &v.to_be_bytes()).unwrap();
+                        }
+                    });
+                }
+                s.spawn(|| {
+                    for k in (0..16).cycle().take(1000) {
+                        for v in quack.read_shared(k).unwrap() {
+                            let v = u64::from_be_bytes(v.try_into().unwrap());
+                            assert_eq!(v % 16, k);
+                        }
+                    }
+                });
+            });
+
+            assert!(quack.write_shared(0, b"").is_err());
+            let mut seen = (0..16)
+                .flat_map(|k| quack.read_shared(k).unwrap())
+                .map(|v| u64::from_be_bytes(v.try_into().unwrap()))
+                .collect::<Vec<_>>();
+            seen.sort();
+            assert!(seen.into_iter().eq(0..threads * writes));
+
+            // the bytes can be borrowed again once nobody shares them
+            let mut data = quack.into_inner();
+            let quack = Quack::new(data.as_mut());
+            assert_eq!(quack.verify().unwrap(), threads * writes);
+        }
+    }
+
+    #[test]
+    fn write_shared_needs_concurrent_format() {
+        let size = Format::LEGACY.store_size(4, [5]).unwrap();
+        let quack =
+            Quack::initialize_assume_zeroed(AtomicBuffer::zeroed(size as usize), 4).unwrap();
+        assert!(quack.write_shared(0, b"hello").is_err());
+    }
+}
@@ -0,0 +1,372 @@
+use crate::{Format, OutaBounds, stor};
+
+/// Sizes of the values a workload will write.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ValueSizes {
+    /// Every value is this many bytes long.
+    Fixed(u64),
+    /// Pairs of a value length and its share of the values, in any unit.
+    /// Each length is budgeted its share of the entries, rounded up.
+    Distribution(Vec<(u64, u64)>),
+}
+
+/// What a [SizingPlan] picks the slot count for.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SizingTarget {
+    /// Enough slots that fewer than one slot is expected to hold more than
+    /// this many values.
+    MaxChain(u64),
+    /// Values per slot.
+    LoadFactor(f64),
+    /// Exactly this many slots, for when the slot count is already settled.
+    Slots(u64),
+}
+
+/// A recommended slot count and buffer size for a workload.
+///
+/// Keys are assumed to be hashes, so the values in a slot follow a Poisson
+/// distribution with the load factor as its mean. Values are sized as stored
+/// uncompressed, and sparse formats as if no two values shared a slot, so the
+/// size is an upper bound for builders using those options. Zstd also stores
+/// the dictionary it trains in the buffer, which is only budgeted for by
+/// [SizingPlan::for_format_with_dictionary].
+#[derive(Clone, Debug, PartialEq)]
+pub struct SizingPlan {
+    pub num_slots: u64,
+    /// Bytes of buffer needed to write every value.
+    pub size: u64,
+    /// Expected number of slots by how many values they hold, starting from
+    /// chains of `chain_lengths_start` values, compare
+    /// [crate::QuackStats::chain_lengths]. Lengths past the end are expected
+    /// to be held by less than one slot in total, and lengths before the start
+    /// by next to none.
+    pub chain_lengths: Vec<f64>,
+    pub chain_lengths_start: u64,
+}
+
+impl SizingPlan {
+    /// Plans for a quack in [Format::LEGACY], as written by
+    /// [crate::Quack::initialize_assume_zeroed].
+    pub fn for_workload(
+        expected_entries: u64,
+        value_sizes: &ValueSizes,
+        target: SizingTarget,
+    ) -> Result<Self, OutaBounds> {
+        Self::for_format(Format::LEGACY, expected_entries, value_sizes, target)
+    }
+
+    /// Plans for a quack in the given format. Fails if the target can't be
+    /// met, or the buffer would be too large for the format's slots to address.
+    pub fn for_format(
+        format: Format,
+        expected_entries: u64,
+        value_sizes: &ValueSizes,
+        target: SizingTarget,
+    ) -> Result<Self, OutaBounds> {
+        Self::for_format_with_dictionary(format, 0, expected_entries, value_sizes, target)
+    }
+
+    /// Like [SizingPlan::for_format], with room for a dictionary of up to
+    /// `dictionary_size` bytes, as [crate::Compression::Zstd] may train one
+    /// that large and store it in the buffer.
+    pub fn for_format_with_dictionary(
+        format: Format,
+        dictionary_size: u64,
+        expected_entries: u64,
+        value_sizes: &ValueSizes,
+        target: SizingTarget,
+    ) -> Result<Self, OutaBounds> {
+        let num_slots = slots_for(expected_entries, target)?;
+        let mut layout = stor::Layout::new(format, num_slots);
+        layout.occupied_slots = if format.sparse_slots() {
+            expected_entries.min(num_slots)
+        } else {
+            num_slots
+        };
+        let size = store_bytes(format, expected_entries, value_sizes)?
+            .checked_add(layout.store_start()?)
+            .and_then(|size| size.checked_add(dictionary_size))
+            .ok_or(OutaBounds)?;
+        if size.saturating_sub(1) > format.slot_width().max_offset() {
+            return Err(OutaBounds);
+        }
+        let (chain_lengths_start, chain_lengths) = chain_lengths(expected_entries, num_slots);
+        Ok(SizingPlan {
+            num_slots,
+            size,
+            chain_lengths,
+            chain_lengths_start,
+        })
+    }
+
+    /// Expected values per slot.
+    pub fn load_factor(&self) -> f64 {
+        self.chain_lengths
+            .iter()
+            .enumerate()
+            .map(|(i, slots)| (self.chain_lengths_start + i as u64) as f64 * slots)
+            .sum::<f64>()
+            / self.num_slots as f64
+    }
+}
+
+fn slots_for(entries: u64, target: SizingTarget) -> Result<u64, OutaBounds> {
+    let slots = match target {
+        SizingTarget::Slots(slots) => slots,
+        SizingTarget::LoadFactor(load_factor) => {
+            if load_factor.is_nan() || load_factor <= 0.0 {
+                return Err(OutaBounds);
+            }
+            let slots = (entries as f64 / load_factor).ceil();
+            if slots >= u64::MAX as f64 {
+                return Err(OutaBounds);
+            }
+            slots as u64
+        }
+        SizingTarget::MaxChain(max_chain) => {
+            if entries <= max_chain {
+                1
+            } else if max_chain == 0 {
+                return Err(OutaBounds);
+            } else {
+                // slots with overlong chains only get rarer as slots are added
+                let overlong = |slots: u64| {
+                    slots as f64 * poisson_tail(entries as f64 / slots as f64, max_chain) >= 1.0
+                };
+                let (mut low, mut high) = (1, entries);
+                while overlong(high) {
+                    low = high;
+                    high = high.checked_mul(2).ok_or(OutaBounds)?;
+                }
+                while high - low > 1 {
+                    let mid = low + (high - low) / 2;
+                    if overlong(mid) {
+                        low = mid;
+                    } else {
+                        high = mid;
+                    }
+                }
+                high
+            }
+        }
+    };
+    Ok(slots.max(1))
+}
+
+/// Bytes of store taken by the values.
+fn store_bytes(format: Format, entries: u64, value_sizes: &ValueSizes) -> Result<u64, OutaBounds> {
+    match value_sizes {
+        ValueSizes::Fixed(len) => format
+            .element_size(*len)?
+            .checked_mul(entries)
+            .ok_or(OutaBounds),
+        ValueSizes::Distribution(shares) => {
+            let total: u128 = shares.iter().map(|(_, share)| u128::from(*share)).sum();
+            if total == 0 {
+                return Err(OutaBounds);
+            }
+            shares.iter().try_fold(0u64, |acc, (len, share)| {
+                let count = (u128::from(entries) * u128::from(*share)).div_ceil(total);
+                let bytes = u128::from(format.element_size(*len)?) * count;
+                u64::try_from(bytes)
+                    .ok()
+                    .and_then(|bytes| acc.checked_add(bytes))
+                    .ok_or(OutaBounds)
+            })
+        }
+    }
+}
+
+/// Probability of a Poisson variable with mean `lambda` exceeding `n`.
+fn poisson_tail(lambda: f64, n: u64) -> f64 {
+    // away from the mean the terms shrink at least as fast as a geometric
+    // series, so either side is summed outwards until they stop adding up
+    let sum = |terms: &mut dyn Iterator<Item = f64>| {
+        let mut sum = 0.0;
+        for term in terms {
+            if term <= sum * f64::EPSILON || term < f64::MIN_POSITIVE {
+                break;
+            }
+            sum += term;
+        }
+        sum
+    };
+    if lambda > n as f64 {
+        let head = sum(&mut poisson_pmf_down(lambda, n));
+        return (1.0 - head).max(0.0);
+    }
+    sum(&mut poisson_pmf_up(lambda, n + 1))
+}
+
+/// The probabilities of a Poisson variable with mean `lambda` being `k`,
+/// `k + 1`, `k + 2`...
+fn poisson_pmf_up(lambda: f64, k: u64) -> impl Iterator<Item = f64> {
+    let ln_lambda = lambda.ln();
+    (k..).scan(ln_poisson(lambda, k), move |ln_p, k| {
+        let p = ln_p.exp();
+        *ln_p += ln_lambda - ((k + 1) as f64).ln();
+        Some(p)
+    })
+}
+
+/// The probabilities of a Poisson variable with mean `lambda` being `k`,
+/// `k - 1`, and so on down to 0.
+fn poisson_pmf_down(lambda: f64, k: u64) -> impl Iterator<Item = f64> {
+    let ln_lambda = lambda.ln();
+    (0..=k).rev().scan(ln_poisson(lambda, k), move |ln_p, k| {
+        let p = ln_p.exp();
+        *ln_p += (k as f64).ln() - ln_lambda;
+        Some(p)
+    })
+}
+
+/// The log of the probability of a Poisson variable with mean `lambda` being
+/// `k`. Worked out in logs, as e^-lambda alone underflows for large means.
+fn ln_poisson(lambda: f64, k: u64) -> f64 {
+    if lambda == 0.0 {
+        return if k == 0 { 0.0 } else { f64::NEG_INFINITY };
+    }
+    -lambda + k as f64 * lambda.ln() - ln_factorial(k)
+}
+
+fn ln_factorial(k: u64) -> f64 {
+    if k < 16 {
+        return (2..=k).map(|i| (i as f64).ln()).sum();
+    }
+    // Stirling's series, accurate to well within an f64 from here on
+    let k = k as f64;
+    k * k.ln() - k + 0.5 * (2.0 * core::f64::consts::PI * k).ln() + 1.0 / (12.0 * k)
+        - 1.0 / (360.0 * k.powi(3))
+        + 1.0 / (1260.0 * k.powi(5))
+}
+
+/// Expected number of slots by chain length, and the length the first entry
+/// is for. Only lengths around the mean are held by enough slots to matter,
+/// so those are all that is worked out, however large the mean.
+fn chain_lengths(entries: u64, num_slots: u64) -> (u64, Vec<f64>) {
+    let lambda = entries as f64 / num_slots as f64;
+    let slots = num_slots as f64;
+    let mode = lambda.floor() as u64;
+    let mut below = poisson_pmf_down(lambda, mode).map(|p| slots * p);
+    let mut chain_lengths = Vec::from_iter(below.next());
+    chain_lengths.extend(below.take_while(|expected| *expected >= f64::EPSILON));
+    chain_lengths.reverse();
+    let start = mode + 1 - chain_lengths.len() as u64;
+
+    let mut accounted = chain_lengths.iter().sum::<f64>();
+    for p in poisson_pmf_up(lambda, mode + 1) {
+        let last = chain_lengths.last().copied().unwrap_or(0.0);
+        if slots - accounted < 1.0 || last < f64::EPSILON {
+            break;
+        }
+        chain_lengths.push(slots * p);
+        accounted += slots * p;
+    }
+    (start, chain_lengths)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ElementEncoding, Quack, calculate_store_size};
+
+    #[test]
+    fn plans() {
+        let plan =
+            SizingPlan::for_workload(1000, &ValueSizes::Fixed(32), SizingTarget::LoadFactor(2.0))
+                .unwrap();
+        assert_eq!(plan.num_slots, 500);
+        assert_eq!(
+            plan.size,
+            calculate_store_size(500, std::iter::repeat_n(32, 1000)).unwrap()
+        );
+        assert!((plan.load_factor() - 2.0).abs() < 0.01);
+        assert!((plan.chain_lengths.iter().sum::<f64>() - 500.0).abs() < 1.0);
+
+        // the plan holds every value
+        let mut quack = Quack::initialize_assume_zeroed(vec![0; plan.size as usize], 500).unwrap();
+        for k in 0..1000 {
+            quack.write(k, &[0; 32]).unwrap();
+        }
+        assert_eq!(quack.remaining().unwrap(), 0);
+
+        let plan =
+            SizingPlan::for_workload(1000, &ValueSizes::Fixed(32), SizingTarget::MaxChain(4))
+                .unwrap();
+        let overlong = |slots: u64| slots as f64 * poisson_tail(1000.0 / slots as f64, 4);
+        assert!(overlong(plan.num_slots) < 1.0);
+        assert!(overlong(plan.num_slots - 1) >= 1.0);
+        assert!(plan.chain_lengths.len() <= 5);
+
+        let sizes = ValueSizes::Distribution(vec![(10, 3), (200, 1)]);
+        let format = Format::new().with_elements(ElementEncoding::Compact);
+        let plan =
+            SizingPlan::for_format(format, 4, &sizes, SizingTarget::LoadFactor(1.0)).unwrap();
+        assert_eq!(plan.size, format.store_size(4, [10, 10, 10, 200]).unwrap());
+
+        assert!(SizingPlan::for_workload(10, &sizes, SizingTarget::MaxChain(0)).is_err());
+        assert!(SizingPlan::for_workload(10, &sizes, SizingTarget::LoadFactor(0.0)).is_err());
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn dictionary() {
+        let dictionary_size = 65536;
+        let compression = crate::Compression::Zstd {
+            level: 3,
+            dictionary_size: dictionary_size as usize,
+        };
+        let mut builder = crate::QuackBuilder::new(4096).compression(compression);
+        // mostly noise, so values barely compress, and the dictionary is
+        // all the plan could miss
+        let mut state = 1u64;
+        for k in 0..2000u64 {
+            let mut value = b"quack quack ".to_vec();
+            while value.len() < 95 {
+                state = state.wrapping_mul(6364136223846793005).wrapping_add(1);
+                value.push((state >> 56) as u8);
+            }
+            builder.insert(k, &value).unwrap();
+        }
+        assert!(builder.stats().unwrap().dictionary_bytes > 0);
+
+        let format = Format::new().with_codec(crate::Codec::Zstd);
+        let plan = SizingPlan::for_format_with_dictionary(
+            format,
+            dictionary_size,
+            2000,
+            &ValueSizes::Fixed(95),
+            SizingTarget::Slots(4096),
+        )
+        .unwrap();
+        assert!(plan.size >= builder.size().unwrap());
+    }
+
+    #[test]
+    fn long_chains() {
+        // every value in one slot, so only chains about as long as the mean matter
+        let entries = 50_000_000;
+        let plan = SizingPlan::for_workload(entries, &ValueSizes::Fixed(8), SizingTarget::Slots(1))
+            .unwrap();
+        assert!(plan.chain_lengths.len() < 100_000);
+        let end = plan.chain_lengths_start + plan.chain_lengths.len() as u64;
+        assert!((plan.chain_lengths_start..end).contains(&entries));
+
+        let plan = SizingPlan::for_workload(
+            1 << 50,
+            &ValueSizes::Fixed(8),
+            SizingTarget::MaxChain(1 << 30),
+        )
+        .unwrap();
+        assert!(plan.chain_lengths.len() < 10_000_000);
+        let overlong = plan.num_slots as f64 * poisson_tail(plan.load_factor(), 1 << 30);
+        assert!(overlong < 1.0);
+
+        let plan =
+            SizingPlan::for_workload(0, &ValueSizes::Fixed(8), SizingTarget::Slots(10)).unwrap();
+        assert_eq!(
+            (plan.chain_lengths_start, plan.chain_lengths),
+            (0, vec![10.0])
+        );
+    }
+}
@@ -0,0 +1,212 @@
+use std::borrow::Cow;
+
+use crate::migrate::merge_layers;
+use crate::{OutaBounds, Quack, QuackBuilder, Sequence};
+
+/// Several quacks read as one, such as one per hour of a pipeline's output.
+///
+/// Reads chain the values from each layer, newest layer first. With
+/// shadowing enabled only the newest layer holding anything for the key's
+/// slot is consulted, so newer layers replace, rather than add to, older ones.
+///
+/// Quacks don't store keys, so layers shadow whole slots: a newer layer
+/// holding only a different key that shares the slot still hides every older
+/// value for the key read, in reads and merges alike. Give layers enough
+/// slots that keys rarely share one.
+pub struct QuackStack<B> {
+    /// Oldest first.
+    layers: Vec<Quack<B>>,
+    shadowing: bool,
+}
+
+impl<B> Default for QuackStack<B> {
+    fn default() -> Self {
+        QuackStack {
+            layers: Vec::new(),
+            shadowing: false,
+        }
+    }
+}
+
+impl<B> QuackStack<B> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Only read from the newest layer with values in a key's slot.
+    pub fn shadowing(mut self, shadowing: bool) -> Self {
+        self.shadowing = shadowing;
+        self
+    }
+
+    /// Adds a layer on top of the others.
+    pub fn push(&mut self, layer: Quack<B>) {
+        self.layers.push(layer);
+    }
+
+    /// Removes the newest layer.
+    pub fn pop(&mut self) -> Option<Quack<B>> {
+        self.layers.pop()
+    }
+
+    /// The layers, oldest first.
+    pub fn layers(&self) -> &[Quack<B>] {
+        &self.layers
+    }
+
+    pub fn into_layers(self) -> Vec<Quack<B>> {
+        self.layers
+    }
+}
+
+impl<B: AsRef<[u8]>> QuackStack<B> {
+    /// The values for a given key, from the newest layer to the oldest.
+    pub fn read(&self, k: u64) -> Result<StackSequence<'_>, OutaBounds> {
+        let mut sequences = Vec::new();
+        for layer in self.layers.iter().rev() {
+            let sequence = layer.read(k)?;
+            if self.shadowing {
+                if sequence.clone().try_next()?.is_some() {
+                    sequences.push(sequence);
+                    break;
+                }
+            } else {
+                sequences.push(sequence);
+            }
+        }
+        // read from the end, so the newest goes last
+        sequences.reverse();
+        Ok(StackSequence { sequences })
+    }
+
+    /// Inserts the contents of every layer into `builder`. Every layer, and
+    /// the builder, must have the same number of slots.
+    pub fn merge_into(&self, mut builder: QuackBuilder) -> Result<QuackBuilder, OutaBounds> {
+        merge_layers(&self.layers, self.shadowing, &mut builder, |_, _| Ok(()))?;
+        Ok(builder)
+    }
+}
+
+/// An iterator over the values for a key in a [QuackStack], newest first.
+///
+/// Iterating decompresses values stored compressed, like [Sequence] does.
+pub struct StackSequence<'a> {
+    /// The layers left to read, oldest first.
+    sequences: Vec<Sequence<'a>>,
+}
+
+impl<'a> Iterator for StackSequence<'a> {
+    type Item = Cow<'a, [u8]>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.try_next_decoded().ok().unwrap_or(None)
+    }
+}
+
+impl<'a> StackSequence<'a> {
+    /// Like [Sequence::try_next], moving on to older layers as each runs out.
+    pub fn try_next(&mut self) -> Result<Option<&'a [u8]>, OutaBounds> {
+        self.try_next_with(Sequence::try_next)
+    }
+
+    /// Like [Sequence::try_next_decoded], moving on to older layers as each
+    /// runs out.
+    pub fn try_next_decoded(&mut self) -> Result<Option<Cow<'a, [u8]>>, OutaBounds> {
+        self.try_next_with(Sequence::try_next_decoded)
+    }
+
+    fn try_next_with<T>(
+        &mut self,
+        mut next: impl FnMut(&mut Sequence<'a>) -> Result<Option<T>, OutaBounds>,
+    ) -> Result<Option<T>, OutaBounds> {
+        while let Some(sequence) = self.sequences.last_mut() {
+            if let Some(v) = next(sequence)? {
+                return Ok(Some(v));
+            }
+            self.sequences.pop();
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::write_u64;
+
+    fn layer(entries: &[(u64, &[u8])]) -> Quack<Vec<u8>> {
+        let mut builder = QuackBuilder::new(4);
+        for (k, v) in entries {
+            builder.insert(*k, v).unwrap();
+        }
+        builder.build().unwrap()
+    }
+
+    fn stack(shadowing: bool) -> QuackStack<Vec<u8>> {
+        let mut stack = QuackStack::new().shadowing(shadowing);
+        stack.push(layer(&[(0, b"hello"), (1, b"world")]));
+        stack.push(layer(&[(0, b"again"), (0, b"quack")]));
+        stack.push(layer(&[(2, b"other")]));
+        stack
+    }
+
+    #[test]
+    fn newest_first() {
+        let stack = stack(false);
+        let items = stack.read(0).unwrap().collect::<Vec<_>>();
+        assert_eq!(items, [&b"quack"[..], b"again", b"hello"]);
+        assert_eq!(stack.read(1).unwrap().collect::<Vec<_>>(), [&b"world"[..]]);
+        assert!(stack.read(3).unwrap().next().is_none());
+    }
+
+    #[test]
+    fn shadowing() {
+        let stack = stack(true);
+        let items = stack.read(0).unwrap().collect::<Vec<_>>();
+        assert_eq!(items, [&b"quack"[..], b"again"]);
+        assert_eq!(stack.read(1).unwrap().collect::<Vec<_>>(), [&b"world"[..]]);
+    }
+
+    #[test]
+    fn shadowing_whole_slots() {
+        let mut stack = stack(true);
+        // 5 shares a slot with 1, and hides it, as keys aren't stored
+        stack.push(layer(&[(5, b"other")]));
+        assert_eq!(stack.read(1).unwrap().collect::<Vec<_>>(), [&b"other"[..]]);
+        assert_eq!(stack.read(5).unwrap().collect::<Vec<_>>(), [&b"other"[..]]);
+    }
+
+    #[test]
+    fn merge() {
+        for shadowing in [false, true] {
+            let stack = stack(shadowing);
+            let merged = stack
+                .merge_into(QuackBuilder::new(4))
+                .unwrap()
+                .build()
+                .unwrap();
+            for k in 0..4 {
+                let expected = stack.read(k).unwrap().collect::<Vec<_>>();
+                assert_eq!(merged.read(k).unwrap().collect::<Vec<_>>(), expected);
+            }
+            assert!(stack.merge_into(QuackBuilder::new(3)).is_err());
+        }
+    }
+
+    #[test]
+    fn damaged_layer() {
+        let mut damaged = Quack::initialize_assume_zeroed(vec![0u8; 256], 4).unwrap();
+        damaged.write(0, b"hello").unwrap();
+        damaged.write(0, b"world").unwrap();
+        // point the second element past the end of the buffer
+        let mut data = damaged.into_inner();
+        write_u64(&mut data, 69, 1000).unwrap();
+
+        let mut stack = stack(false);
+        stack.push(Quack::new(data));
+        let mut items = stack.read(0).unwrap();
+        assert_eq!(items.try_next_decoded().unwrap().unwrap(), &b"world"[..]);
+        assert!(items.try_next_decoded().is_err());
+        assert!(stack.merge_into(QuackBuilder::new(4)).is_err());
+    }
+}
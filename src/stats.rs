@@ -0,0 +1,162 @@
+use crate::{OutaBounds, QuackSnapshot, val};
+
+/// What [QuackSnapshot::stats] found, handy for picking a slot count.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct QuackStats {
+    pub num_slots: u64,
+    /// Slots looked at, all of them unless sampled.
+    pub slots_examined: u64,
+    /// Values in the examined slots.
+    pub entries: u64,
+    /// Examined slots holding at least one value.
+    pub occupied_slots: u64,
+    /// Number of examined slots by how many values they hold.
+    pub chain_lengths: Vec<u64>,
+    pub max_chain: u64,
+    /// Bytes of store in use, store_len.
+    pub used: u64,
+    /// Bytes of store the buffer has room for, in use or not.
+    pub capacity: u64,
+    /// Bytes spent on next pointers and lengths by the examined values.
+    pub overhead_bytes: u64,
+    /// Bytes of payload of the examined values, as stored.
+    pub payload_bytes: u64,
+    /// Number of examined values by payload size: bucket 0 counts empty
+    /// payloads, bucket n those of 2^(n-1) to 2^n - 1 bytes.
+    pub payload_sizes: Vec<u64>,
+}
+
+impl QuackStats {
+    /// Whether only some slots were examined.
+    pub fn is_sampled(&self) -> bool {
+        self.slots_examined < self.num_slots
+    }
+
+    /// Values per slot.
+    pub fn load_factor(&self) -> f64 {
+        self.entries as f64 / self.slots_examined.max(1) as f64
+    }
+
+    /// Values in the whole quack, extrapolated from the examined slots.
+    pub fn estimated_entries(&self) -> u64 {
+        self.scale(self.entries)
+    }
+
+    /// Occupied slots in the whole quack, extrapolated from the examined slots.
+    pub fn estimated_occupied_slots(&self) -> u64 {
+        self.scale(self.occupied_slots)
+    }
+
+    /// Mean bytes of bookkeeping per value.
+    pub fn mean_overhead(&self) -> f64 {
+        self.overhead_bytes as f64 / self.entries.max(1) as f64
+    }
+
+    fn scale(&self, count: u64) -> u64 {
+        if self.slots_examined == 0 {
+            return 0;
+        }
+        let scaled =
+            u128::from(count) * u128::from(self.num_slots) / u128::from(self.slots_examined);
+        scaled as u64
+    }
+}
+
+impl QuackSnapshot<'_> {
+    /// Walks every chain to describe how full the quack is and how values
+    /// are spread over slots. See [QuackSnapshot::sampled_stats] for large quacks.
+    pub fn stats(&self) -> Result<QuackStats, OutaBounds> {
+        self.sampled_stats(self.layout.num_slots)
+    }
+
+    /// Like [QuackSnapshot::stats], but only walks about `slots` slots, evenly
+    /// spaced, so the cost doesn't grow with the size of the quack. Counts only
+    /// cover the examined slots, see [QuackStats::estimated_entries].
+    pub fn sampled_stats(&self, slots: u64) -> Result<QuackStats, OutaBounds> {
+        let layout = &self.layout;
+        let num_slots = layout.num_slots;
+        let store_start = layout.store_start()?;
+        let mut stats = QuackStats {
+            num_slots,
+            used: self.store_len()?,
+            capacity: (self.data.len() as u64).saturating_sub(store_start),
+            ..QuackStats::default()
+        };
+        // like verify, a walk longer than the buffer has room for must loop
+        let max_elements = stats.capacity / layout.format.element_size(0)?;
+        let mut steps = 0u64;
+        let stride = num_slots.div_ceil(slots.max(1)).max(1);
+        for slot_index in (0..num_slots).step_by(stride as usize) {
+            stats.slots_examined += 1;
+            let mut len = 0u64;
+            let mut next = layout.read_slot(self.data, slot_index)?;
+            while next != 0 {
+                steps += 1;
+                if steps > max_elements {
+                    return Err(OutaBounds);
+                }
+                let element = val::read(self.data, layout.format, next)?;
+                if next < self.watermark {
+                    let payload_len = element.payload.len() as u64;
+                    len += 1;
+                    stats.payload_bytes += payload_len;
+                    stats.overhead_bytes += val::overhead(layout.format, payload_len)?;
+                    let bucket = (u64::BITS - payload_len.leading_zeros()) as usize;
+                    bump(&mut stats.payload_sizes, bucket);
+                }
+                next = element.next;
+            }
+            stats.entries += len;
+            stats.occupied_slots += u64::from(len > 0);
+            stats.max_chain = stats.max_chain.max(len);
+            bump(&mut stats.chain_lengths, len as usize);
+        }
+        Ok(stats)
+    }
+}
+
+fn bump(histogram: &mut Vec<u64>, bucket: usize) {
+    if histogram.len() <= bucket {
+        histogram.resize(bucket + 1, 0);
+    }
+    histogram[bucket] += 1;
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Quack, read_u64, write_u64};
+
+    #[test]
+    fn stats() {
+        let mut quack = Quack::initialize_assume_zeroed(vec![0u8; 1024], 8).unwrap();
+        for (k, v) in [(1, &b""[..]), (1, b"a"), (2, b"quack"), (9, &[0; 300])] {
+            quack.write(k, v).unwrap();
+        }
+        let stats = quack.stats().unwrap();
+        assert_eq!(stats.entries, 4);
+        assert_eq!(stats.occupied_slots, 2);
+        assert_eq!(stats.chain_lengths, [6, 1, 0, 1]);
+        assert_eq!(stats.max_chain, 3);
+        assert_eq!(stats.load_factor(), 0.5);
+        assert_eq!(stats.payload_bytes, 306);
+        assert_eq!(stats.overhead_bytes, 4 * 16);
+        assert_eq!(stats.mean_overhead(), 16.0);
+        assert_eq!(stats.payload_sizes, [1, 1, 0, 1, 0, 0, 0, 0, 0, 1]);
+        assert_eq!(stats.used, 306 + 4 * 16);
+        assert_eq!(stats.capacity, 1024 - 16 - 8 * 8);
+        assert!(!stats.is_sampled());
+
+        let sampled = quack.sampled_stats(4).unwrap();
+        assert_eq!(sampled.slots_examined, 4);
+        // slots 0, 2, 4 and 6
+        assert_eq!(sampled.entries, 1);
+        assert_eq!(sampled.estimated_entries(), 2);
+        assert!(sampled.is_sampled());
+
+        // point the value in slot 2 back at itself
+        let mut data = quack.into_inner();
+        let head = read_u64(&data, 16 + 2 * 8).unwrap();
+        write_u64(&mut data, head, head).unwrap();
+        assert!(Quack::new(data).stats().is_err());
+    }
+}
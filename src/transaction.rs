@@ -0,0 +1,138 @@
+use std::collections::BTreeMap;
+
+use crate::{OutaBounds, stor};
+
+/// A batch of writes, see [crate::Quack::transaction].
+///
+/// Writes are appended to the store right away, but nothing points at them
+/// until [Transaction::commit] updates the slots and then store_len. Reads
+/// through a [crate::Quack::snapshot] skip elements past store_len, so they
+/// see all of the batch or none of it.
+/// If the commit is cut short by a crash, [crate::Quack::recover] rolls back the
+/// slots it got to.
+///
+/// Dropping a transaction without committing it discards its writes.
+pub struct Transaction<'a> {
+    data: &'a mut [u8],
+    layout: stor::Layout,
+    /// store_len including the uncommitted writes.
+    store_len: u64,
+    /// New heads of the slots written to, by slot index.
+    heads: BTreeMap<u64, u64>,
+}
+
+impl<'a> Transaction<'a> {
+    pub(crate) fn new(data: &'a mut [u8]) -> Result<Self, OutaBounds> {
+        let layout = stor::Layout::read(data)?;
+        let store_len = layout.read_store_len(data)?;
+        Ok(Transaction {
+            data,
+            layout,
+            store_len,
+            heads: BTreeMap::new(),
+        })
+    }
+
+    /// Like [crate::Quack::write], but only visible once the transaction commits.
+    pub fn write(&mut self, k: u64, v: &[u8]) -> Result<(), OutaBounds> {
+        let slot_index = k.checked_rem(self.layout.num_slots).ok_or(OutaBounds)?;
+        // fail now rather than at commit for slots a sparse quack has no room for
+        self.layout
+            .slot_offset(self.data, slot_index)?
+            .ok_or(OutaBounds)?;
+
+        let old_head = match self.heads.get(&slot_index) {
+            Some(head) => *head,
+            None => self.layout.read_slot(self.data, slot_index)?,
+        };
+        let (new_head, new_len) =
+            crate::append(self.data, &self.layout, self.store_len, old_head, v)?;
+        self.heads.insert(slot_index, new_head);
+        self.store_len = new_len;
+        Ok(())
+    }
+
+    /// Number of slots written to so far.
+    pub fn slots_touched(&self) -> usize {
+        self.heads.len()
+    }
+
+    /// Publishes every write in the transaction.
+    pub fn commit(self) -> Result<(), OutaBounds> {
+        for (slot_index, head) in &self.heads {
+            self.layout.write_slot(self.data, *slot_index, *head)?;
+        }
+        self.layout.write_store_len(self.data, self.store_len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Format, Quack};
+
+    fn quack() -> Quack<Vec<u8>> {
+        let size = Format::new().store_size(4, [5; 8]).unwrap();
+        Format::new()
+            .initialize_assume_zeroed(vec![0u8; size as usize], 4)
+            .unwrap()
+    }
+
+    #[test]
+    fn commit_publishes_everything() {
+        let mut quack = quack();
+        quack.write(0, b"first").unwrap();
+
+        let mut transaction = quack.transaction().unwrap();
+        transaction.write(0, b"hello").unwrap();
+        transaction.write(1, b"world").unwrap();
+        transaction.write(0, b"again").unwrap();
+        assert_eq!(transaction.slots_touched(), 2);
+        transaction.commit().unwrap();
+
+        let items = quack.read(0).unwrap().collect::<Vec<_>>();
+        assert_eq!(items, [&b"again"[..], b"hello", b"first"]);
+        assert_eq!(quack.read(1).unwrap().collect::<Vec<_>>(), [&b"world"[..]]);
+    }
+
+    #[test]
+    fn dropped_transaction_leaves_no_trace() {
+        let mut quack = quack();
+        let mut transaction = quack.transaction().unwrap();
+        transaction.write(0, b"hello").unwrap();
+        drop(transaction);
+
+        assert!(quack.read(0).unwrap().next().is_none());
+        quack.write(1, b"world").unwrap();
+        assert_eq!(quack.read(1).unwrap().collect::<Vec<_>>(), [&b"world"[..]]);
+    }
+
+    #[test]
+    fn recover_rolls_back_torn_commit() {
+        let mut quack = quack();
+        quack.write(0, b"first").unwrap();
+
+        // a commit that crashed after updating slots but before store_len
+        let data = quack.data.as_mut_slice();
+        let layout = stor::Layout::read(data).unwrap();
+        let mut transaction = Transaction::new(data).unwrap();
+        transaction.write(0, b"hello").unwrap();
+        transaction.write(1, b"world").unwrap();
+        for (slot_index, head) in &transaction.heads {
+            layout
+                .write_slot(transaction.data, *slot_index, *head)
+                .unwrap();
+        }
+
+        let snapshot = quack.snapshot().unwrap();
+        assert_eq!(
+            snapshot.read(0).unwrap().collect::<Vec<_>>(),
+            [&b"first"[..]]
+        );
+        assert_eq!(quack.recover().unwrap(), 2);
+        assert_eq!(quack.recover().unwrap(), 0);
+        quack.write(1, b"quack").unwrap();
+        assert_eq!(quack.read(0).unwrap().collect::<Vec<_>>(), [&b"first"[..]]);
+        assert_eq!(quack.read(1).unwrap().collect::<Vec<_>>(), [&b"quack"[..]]);
+    }
+}
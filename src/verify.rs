@@ -0,0 +1,97 @@
+use std::borrow::Cow;
+
+use crate::{OutaBounds, QuackSnapshot, compress, stor, val};
+
+impl QuackSnapshot<'_> {
+    /// Checks the quack is intact: the store fits in the buffer, every list
+    /// stays inside the store and ends, and every value can be decoded.
+    /// Returns the number of values.
+    ///
+    /// Unlike reads, which only notice damage along the lists they follow,
+    /// this looks at everything, so it takes as long as a full scan.
+    pub fn verify(&self) -> Result<u64, OutaBounds> {
+        let data = self.data;
+        let format = self.layout.format;
+        let store_start = self.layout.store_start()?;
+        if self.watermark > data.len() as u64 {
+            return Err(OutaBounds);
+        }
+        let dictionary = self.layout.dictionary(data)?;
+
+        // a list longer than the buffer has room for must loop back on itself
+        let max_elements = (data.len() as u64 - store_start) / format.element_size(0)?;
+        let mut steps = 0u64;
+        let mut entries = 0u64;
+        let mut buf = Vec::new();
+        let mut decompressor = compress::Decompressor::new(self.dictionary);
+        for slot_index in 0..self.layout.num_slots {
+            let mut next = self.layout.read_slot(data, slot_index)?;
+            while next != 0 {
+                steps += 1;
+                if steps > max_elements || next < store_start {
+                    return Err(OutaBounds);
+                }
+                let element = val::read(data, format, next)?;
+                if next < self.watermark {
+                    let resolved = val::resolve(data, format, next)?;
+                    if resolved.tags.compressed {
+                        decompressor.decompress_into(
+                            format.codec(),
+                            || Ok(Cow::Borrowed(dictionary)),
+                            resolved.payload,
+                            &mut buf,
+                        )?;
+                    }
+                    entries += 1;
+                }
+                next = element.next;
+            }
+        }
+        for element in self.scan_store()? {
+            element?;
+        }
+        Ok(entries)
+    }
+}
+
+/// Checks every slot is empty or leads into the store below `watermark`, so
+/// appends starting there can't overwrite an element a slot points at.
+pub(crate) fn check_slot_heads(
+    data: &[u8],
+    layout: &stor::Layout,
+    watermark: u64,
+) -> Result<(), OutaBounds> {
+    let store = layout.store_start()?..watermark;
+    for slot_index in 0..layout.num_slots {
+        let head = layout.read_slot(data, slot_index)?;
+        if head != 0 && !store.contains(&head) {
+            return Err(OutaBounds);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Quack, QuackBuilder, write_u64};
+
+    #[test]
+    fn verify() {
+        let mut builder = QuackBuilder::new(4);
+        for k in 0..10u64 {
+            builder.insert(k, &k.to_be_bytes()).unwrap();
+        }
+        let quack = builder.build().unwrap();
+        assert_eq!(quack.verify().unwrap(), 10);
+
+        let mut quack = Quack::initialize_assume_zeroed(vec![0u8; 256], 2).unwrap();
+        quack.write(0, b"hello").unwrap();
+        quack.write(0, b"world").unwrap();
+        assert_eq!(quack.verify().unwrap(), 2);
+
+        // point the first element at the second, which points back at it
+        let mut data = quack.into_inner();
+        write_u64(&mut data, 32, 53).unwrap();
+        assert!(Quack::new(data).verify().is_err());
+    }
+}